@@ -0,0 +1,445 @@
+//! Deduplicate the `node_modules` directories of npm projects into a shared repository.
+//!
+//! Every subcommand prints the instructions it produces, either in human readable form or as one
+//! JSON object per line with `--json`. The exit code is non-zero if any error occurred, if `verify`
+//! found issues, or if `check-lock` found discrepancies.
+#[macro_use]
+extern crate clap;
+extern crate npm_tools;
+extern crate serde_json;
+
+use std::error::Error as StdError;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use npm_tools::{DeduplicationOptions, Error, ExecutionError, FileStrategy, Finding, Instruction, JournaledExecutor,
+                LinkMode, LockDiscrepancy, PackageInfo, Plan, PlatformSegment, PnpmLayout, Recovery, SymlinkStyle,
+                Visitor, apply_plan, cross_check_lock, deduplicate_into_with, find_locked_packages,
+                find_packages_parallel, find_yarn_locked_packages, gc, gc_report, record_content_hashes_of, recover,
+                revert, unregister_project, verify_repo};
+use serde_json::{Map, Value};
+
+/// A visitor which prints all instructions, and applies them if it has an executor.
+struct Reporter {
+    json: bool,
+    executor: Option<JournaledExecutor>,
+    /// The directories packages were moved or linked into by the applied instructions.
+    ingested: Vec<PathBuf>,
+}
+
+impl Reporter {
+    /// Returns a reporter which applies all instructions if `repo` is set, journaling them within it.
+    fn new(args: &ArgMatches, repo: Option<&Path>) -> Reporter {
+        Reporter {
+            json: args.is_present("json"),
+            executor: repo.map(|repo| {
+                JournaledExecutor::new(repo).unwrap_or_else(|err| fail(String::from("Cannot apply changes"), &err))
+            }),
+            ingested: Vec::new(),
+        }
+    }
+}
+
+impl Visitor for Reporter {
+    type Error = ExecutionError;
+
+    fn error(&mut self, _package: &PackageInfo, _err: &Error) {}
+
+    fn warning(&mut self, _package: &PackageInfo, err: &Error) {
+        eprintln!("warning: {}", err);
+    }
+
+    fn change(&mut self, action: Instruction) -> Result<(), Self::Error> {
+        if self.json {
+            println!("{}", action.to_json());
+        } else {
+            println!("{}", action);
+        }
+        let executor = match self.executor {
+            Some(ref mut executor) => executor,
+            None => return Ok(()),
+        };
+        executor.change(action.clone())?;
+        match action {
+            Instruction::MoveAndSymlink { to_here, .. } |
+            Instruction::HardlinkFiles { to_here, .. } |
+            Instruction::CloneFiles { to_here, .. } => self.ingested.push(to_here.to_owned()),
+            _ => {}
+        }
+        let linked = executor.take_linked_files();
+        if !linked.is_empty() {
+            let count = |strategy| linked.iter().filter(|f| f.strategy == strategy).count() as u64;
+            let (cloned, hardlinked, copied) =
+                (count(FileStrategy::Reflink), count(FileStrategy::Hardlink), count(FileStrategy::Copy));
+            print_json(self.json,
+                       vec![("cloned_files", Value::U64(cloned)),
+                            ("hardlinked_files", Value::U64(hardlinked)),
+                            ("copied_files", Value::U64(copied))],
+                       format!("  {} file(s) cloned, {} hard linked, {} copied", cloned, hardlinked, copied));
+        }
+        Ok(())
+    }
+}
+
+fn print_errors(errors: &[Error]) {
+    for err in errors {
+        eprintln!("error: {}", err);
+        let mut cause = err.source();
+        while let Some(err) = cause {
+            eprintln!("  caused by: {}", err);
+            cause = err.source();
+        }
+    }
+}
+
+fn print_json(json: bool, fields: Vec<(&str, Value)>, human: String) {
+    if json {
+        let mut m = Map::new();
+        for (k, v) in fields {
+            m.insert(k.to_owned(), v);
+        }
+        println!("{}", Value::Object(m));
+    } else {
+        println!("{}", human);
+    }
+}
+
+fn path_value(p: &Path) -> Value {
+    Value::String(p.to_string_lossy().into_owned())
+}
+
+/// Print `message` along with `err` and exit, for errors which are not related to packages.
+fn fail(message: String, err: &dyn StdError) -> ! {
+    eprintln!("error: {}: {}", message, err);
+    process::exit(1);
+}
+
+fn options(args: &ArgMatches) -> DeduplicationOptions {
+    let mut options = DeduplicationOptions {
+        symlink_style: if args.is_present("relative") {
+            SymlinkStyle::Relative
+        } else {
+            SymlinkStyle::Absolute
+        },
+        link_mode: if args.is_present("hardlink") {
+            LinkMode::Hardlink
+        } else if args.is_present("reflink") {
+            LinkMode::Reflink
+        } else {
+            LinkMode::Symlink
+        },
+        platform_segment: if args.is_present("detect-platform") {
+            PlatformSegment::Detect
+        } else {
+            PlatformSegment::Never
+        },
+        threads: value_t!(args, "threads", usize).unwrap_or_else(|err| err.exit()),
+        compare_contents: args.is_present("compare-contents"),
+        ..Default::default()
+    };
+    if args.is_present("pnpm") {
+        options.repo_layout = Arc::new(PnpmLayout);
+    }
+    options
+}
+
+/// Record the hashes of the packages `reporter` moved into `repo`.
+fn finish_ingestion(repo: &Path, reporter: &Reporter, errors: &mut Vec<Error>) {
+    if let Err(errs) = record_content_hashes_of(repo, &reporter.ingested) {
+        errors.extend(errs);
+    }
+}
+
+fn dedup(args: &ArgMatches, apply: bool) -> Vec<Error> {
+    let project = Path::new(args.value_of("project").expect("clap to check required arguments"));
+    let repo = Path::new(args.value_of("repo").expect("clap to check required arguments"));
+    let options = DeduplicationOptions {
+        register_projects: apply,
+        ..options(args)
+    };
+
+    let mut errors = Vec::new();
+    let mut packages = Vec::new();
+    if args.is_present("lockfile") || args.is_present("yarn-lock") {
+        let locked = if args.is_present("lockfile") {
+            find_locked_packages(project)
+        } else {
+            find_yarn_locked_packages(project)
+        };
+        match locked {
+            Ok(locked) => packages.extend(locked.into_iter().map(|p| p.info)),
+            Err(err) => errors.push(err),
+        }
+    } else {
+        for p in find_packages_parallel(project, options.threads) {
+            match p {
+                Ok(p) => packages.push(p),
+                Err(err) => errors.push(err),
+            }
+        }
+    }
+    match args.value_of("output") {
+        Some(output) => {
+            let mut plan = Plan::new(repo, project);
+            if let Err(errs) = deduplicate_into_with(repo, &packages, &options, &mut plan) {
+                errors.extend(errs);
+            }
+            let mut reporter = Reporter::new(args, None);
+            for instruction in &plan.instructions {
+                reporter.change(instruction.as_instruction()).expect("reporter not to fail when not applying");
+            }
+            let written = File::create(output)
+                .map_err(serde_json::Error::Io)
+                .and_then(|mut f| serde_json::to_writer_pretty(&mut f, &plan));
+            if let Err(err) = written {
+                fail(format!("Failed to write plan to '{}'", output), &err);
+            }
+        }
+        None => {
+            let mut reporter = Reporter::new(args, Some(repo).filter(|_| apply));
+            if let Err(errs) = deduplicate_into_with(repo, &packages, &options, &mut reporter) {
+                errors.extend(errs);
+            }
+            if apply {
+                finish_ingestion(repo, &reporter, &mut errors);
+            }
+        }
+    }
+    errors
+}
+
+fn apply(args: &ArgMatches) -> Vec<Error> {
+    let path = args.value_of("plan").expect("clap to check required arguments");
+    let plan: Plan = match File::open(path).map_err(serde_json::Error::Io).and_then(serde_json::from_reader) {
+        Ok(plan) => plan,
+        Err(err) => fail(format!("Failed to read plan from '{}'", path), &err),
+    };
+    let mut reporter = Reporter::new(args, Some(&plan.repo));
+    let mut errors = apply_plan(&plan, &mut reporter).err().unwrap_or_default();
+    if errors.is_empty() {
+        finish_ingestion(&plan.repo, &reporter, &mut errors);
+    }
+    errors
+}
+
+fn revert_project(args: &ArgMatches) -> Vec<Error> {
+    let project = args.value_of("project").expect("clap to check required arguments");
+    let repo = args.value_of("repo").expect("clap to check required arguments");
+    if let Err(errors) = revert(project, repo, &mut Reporter::new(args, Some(Path::new(repo)))) {
+        return errors;
+    }
+    // The project doesn't use the repository anymore, which must not keep its packages alive
+    unregister_project(repo, project).err().into_iter().collect()
+}
+
+fn collect_garbage(args: &ArgMatches) -> Vec<Error> {
+    let repo = args.value_of("repo").expect("clap to check required arguments");
+    let json = args.is_present("json");
+    let result = if args.is_present("dry-run") {
+        gc_report(repo)
+    } else {
+        gc(repo, &mut Reporter::new(args, Some(Path::new(repo))))
+    };
+    match result {
+        Ok(report) => {
+            print_json(json,
+                       vec![("reclaimable_bytes", Value::U64(report.reclaimable_bytes())),
+                            ("unused_packages", Value::U64(report.unused.len() as u64))],
+                       format!("{} unused package(s), {} bytes reclaimable",
+                               report.unused.len(),
+                               report.reclaimable_bytes()));
+            Vec::new()
+        }
+        Err(errors) => errors,
+    }
+}
+
+fn recover_repo(args: &ArgMatches) -> Vec<Error> {
+    let repo = args.value_of("repo").expect("clap to check required arguments");
+    let recovered = recover(repo).unwrap_or_else(|err| fail(String::from("Recovery failed"), &err));
+    for r in recovered {
+        let recovery = match r.recovery {
+            Recovery::RolledBack => "rolled back",
+            Recovery::Finished => "finished",
+        };
+        print_json(args.is_present("json"),
+                   vec![("recovery", Value::String(recovery.to_owned())),
+                        ("instruction", r.instruction.as_instruction().to_json())],
+                   format!("{}: {}", recovery, r.instruction));
+    }
+    Vec::new()
+}
+
+fn check_lock(args: &ArgMatches) -> (Vec<Error>, usize) {
+    let project = Path::new(args.value_of("project").expect("clap to check required arguments"));
+    let locked = match find_locked_packages(project) {
+        Ok(locked) => locked,
+        Err(err) => return (vec![err], 0),
+    };
+    let mut errors = Vec::new();
+    let mut on_disk = Vec::new();
+    for p in find_packages_parallel(project, 1) {
+        match p {
+            Ok(p) => on_disk.push(p),
+            Err(err) => errors.push(err),
+        }
+    }
+    let discrepancies = cross_check_lock(&locked, &on_disk);
+    for d in &discrepancies {
+        let (kind, details) = match *d {
+            LockDiscrepancy::NotInLock(_) => {
+                ("NotInLock", String::from("found on disk, but missing from the lockfile"))
+            }
+            LockDiscrepancy::NotOnDisk(ref p) => {
+                ("NotOnDisk", format!("{}@{} is locked, but missing on disk", p.name, p.version))
+            }
+        };
+        print_json(args.is_present("json"),
+                   vec![("discrepancy", Value::String(kind.to_owned())),
+                        ("path", path_value(d.directory())),
+                        ("details", Value::String(details.clone()))],
+                   format!("{}: '{}' {}", kind, d.directory().display(), details));
+    }
+    (errors, discrepancies.len())
+}
+
+fn verify(args: &ArgMatches) -> (Vec<Error>, usize) {
+    let repo = args.value_of("repo").expect("clap to check required arguments");
+    let json = args.is_present("json");
+    let issues = match verify_repo(repo) {
+        Ok(issues) => issues,
+        Err(errors) => return (errors, 0),
+    };
+    for issue in &issues {
+        let (kind, path, details) = match issue.finding {
+            Finding::InvalidPackageJson { ref directory, ref reason } => {
+                ("InvalidPackageJson", directory, reason.clone())
+            }
+            Finding::NameMismatch { ref directory, ref expected, ref actual } |
+            Finding::VersionMismatch { ref directory, ref expected, ref actual } => {
+                ("Mismatch", directory, format!("expected '{}', found '{}'", expected, actual))
+            }
+            Finding::DanglingSymlink { ref link, ref target } => {
+                ("DanglingSymlink", link, format!("points to missing '{}'", target.display()))
+            }
+            Finding::EmptyVersionDirectory { ref directory } => ("EmptyVersionDirectory", directory, String::new()),
+            Finding::ModifiedSinceIngestion { ref directory, .. } => {
+                ("ModifiedSinceIngestion", directory, String::from("content hash changed"))
+            }
+        };
+        let repair = issue.repair.as_ref();
+        print_json(json,
+                   vec![("finding", Value::String(kind.to_owned())),
+                        ("path", path_value(path)),
+                        ("details", Value::String(details.clone())),
+                        ("repair", repair.map(|r| r.as_instruction().to_json()).unwrap_or(Value::Null))],
+                   format!("{}: '{}' {}{}",
+                           kind,
+                           path.display(),
+                           details,
+                           repair.map(|r| format!(" (repair: {})", r)).unwrap_or_default()));
+    }
+    (Vec::new(), issues.len())
+}
+
+fn main() {
+    let project = Arg::with_name("project")
+        .required(true)
+        .help("The directory containing the node_modules directory to operate on");
+    let repo = Arg::with_name("repo")
+        .long("repo")
+        .takes_value(true)
+        .required(true)
+        .env("NPM_TOOLS_REPO")
+        .help("The repository to keep deduplicated packages in");
+    let json = Arg::with_name("json").long("json").global(true).help("Print one JSON object per line");
+    let dedup_args = [project.clone(),
+                      repo.clone(),
+                      Arg::with_name("relative").long("relative").help("Use relative symbolic links"),
+                      Arg::with_name("hardlink")
+                          .long("hardlink")
+                          .conflicts_with("relative")
+                          .help("Keep package directories, but hard link their files to the ones in the repository"),
+                      Arg::with_name("reflink")
+                          .long("reflink")
+                          .conflicts_with_all(&["relative", "hardlink"])
+                          .help("As --hardlink, but clone files where the filesystem supports it"),
+                      Arg::with_name("detect-platform")
+                          .long("detect-platform")
+                          .help("Store native addons in a platform specific directory"),
+                      Arg::with_name("pnpm")
+                          .long("pnpm")
+                          .help("Store packages like pnpm, with their dependencies linked next to them"),
+                      Arg::with_name("lockfile")
+                          .long("lockfile")
+                          .help("Take the packages from package-lock.json or npm-shrinkwrap.json instead of \
+                                 searching node_modules"),
+                      Arg::with_name("yarn-lock")
+                          .long("yarn-lock")
+                          .conflicts_with("lockfile")
+                          .help("Take the packages yarn.lock resolves the project's dependencies to instead of \
+                                 searching node_modules"),
+                      Arg::with_name("compare-contents")
+                          .long("compare-contents")
+                          .help("Only replace copies of a package whose contents match the one in the repository"),
+                      Arg::with_name("threads")
+                          .long("threads")
+                          .takes_value(true)
+                          .default_value("1")
+                          .help("The amount of threads to scan node_modules and read package.json files with")];
+
+    let args = App::new("npm-dedup")
+        .about("Deduplicate node_modules directories into a shared repository")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(json)
+        .subcommand(SubCommand::with_name("dedup")
+            .about("Move packages into the repository and link them")
+            .args(&dedup_args))
+        .subcommand(SubCommand::with_name("plan")
+            .about("Print what 'dedup' would do, without changing anything")
+            .args(&dedup_args)
+            .arg(Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .help("Save the plan to the given file, to apply it later with 'apply'")))
+        .subcommand(SubCommand::with_name("apply")
+            .about("Apply a plan saved by 'plan --output', if the filesystem still matches it")
+            .arg(Arg::with_name("plan").required(true).help("The file containing the plan")))
+        .subcommand(SubCommand::with_name("check-lock")
+            .about("Compare the packages within node_modules with the ones in the project's lockfile")
+            .arg(project.clone()))
+        .subcommand(SubCommand::with_name("revert")
+            .about("Replace all links into the repository with copies")
+            .args(&[project, repo.clone()]))
+        .subcommand(SubCommand::with_name("gc")
+            .about("Remove packages from the repository which no registered project uses")
+            .arg(repo.clone())
+            .arg(Arg::with_name("dry-run").long("dry-run").help("Only report what would be removed")))
+        .subcommand(SubCommand::with_name("recover")
+            .about("Roll back or finish changes which were interrupted")
+            .arg(repo.clone()))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Check the repository for inconsistencies")
+            .arg(repo))
+        .get_matches();
+
+    let (errors, issues) = match args.subcommand() {
+        ("dedup", Some(args)) => (dedup(args, true), 0),
+        ("plan", Some(args)) => (dedup(args, false), 0),
+        ("apply", Some(args)) => (apply(args), 0),
+        ("revert", Some(args)) => (revert_project(args), 0),
+        ("gc", Some(args)) => (collect_garbage(args), 0),
+        ("recover", Some(args)) => (recover_repo(args), 0),
+        ("check-lock", Some(args)) => check_lock(args),
+        ("verify", Some(args)) => verify(args),
+        _ => unreachable!("clap to require a subcommand"),
+    };
+
+    print_errors(&errors);
+    if !errors.is_empty() || issues != 0 {
+        process::exit(1);
+    }
+}
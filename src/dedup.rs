@@ -3,17 +3,28 @@ use std::path::PathBuf;
 use serde_json::{self, Value, from_reader, Map};
 use quick_error::ResultExt;
 use std::ffi::OsStr;
-use std::collections::hash_map::{Entry, HashMap};
-use std::collections::hash_set::HashSet;
-use semver::{VersionReq, Version, SemVerError, ReqParseError};
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use semver::{Identifier, Version, SemVerError};
 use std::error::Error as StdError;
+use gc::register_project;
+use hash::{content_hash, read_stored_hash};
+use parallel::parallel_map;
+use paths::{absolute, path_from_json, path_to_json, relative_to, same_file};
+use layout::{NameVersionLayout, RepoLayout};
+use resolve::{best_repo_version, retarget_destination};
+use range::RangeParseError;
+use spec::DependencySpec;
+use platform::{PlatformSegment, current_platform, is_native_package};
 
 use std;
+use std::fmt;
+use std::sync::Arc;
 use std::fs;
 use std::io;
 
-struct ReadPackageFile<'a>(&'a Path);
-struct DecodePackageFile<'a>(&'a Path);
+pub(crate) struct ReadPackageFile<'a>(pub &'a Path);
+pub(crate) struct DecodePackageFile<'a>(pub &'a Path);
 struct VisitorContext<'a>(&'a Path);
 struct PathAndVersion<'a>(&'a Path, &'a str);
 
@@ -26,10 +37,11 @@ quick_error!{
             context(p: ReadPackageFile<'a>, err: io::Error) -> (p.0.to_path_buf(), err)
             cause(err)
         }
-        InvalidVersionRequirement(package_json_dir: PathBuf, version_req: String, err: ReqParseError) {
-            description("A semantic version requirement could not be parsed")
-            display("Unexpected version requirement string '{}' in {}/package.json: {}", version_req, package_json_dir.display(), err)
-            context(a: PathAndVersion<'a>, err: ReqParseError) -> (a.0.to_path_buf(), a.1.to_owned(), err)
+        InvalidVersionRequirement(package_json_dir: PathBuf, version_req: String, err: RangeParseError) {
+            description("A version range could not be parsed")
+            display("Unexpected version requirement string '{}' in {}/package.json: {}",
+                    version_req, package_json_dir.display(), err)
+            context(a: PathAndVersion<'a>, err: RangeParseError) -> (a.0.to_path_buf(), a.1.to_owned(), err)
             cause(err)
         }
         InvalidVersion(package_json_dir: PathBuf, version: String, err: SemVerError) {
@@ -38,6 +50,10 @@ quick_error!{
             context(a: PathAndVersion<'a>, err: SemVerError) -> (a.0.to_path_buf(), a.1.to_owned(), err)
             cause(err)
         }
+        InvalidPackageName(package_json_dir: PathBuf, name: String) {
+            description("A package name was not usable as path within the repository")
+            display("Invalid package name '{}' in {}/package.json", name, package_json_dir.display())
+        }
         JsonStructure(package_json_dir: PathBuf, expectation: String) {
             description("The data structure within package.json was unexpected")
             display("Unexpected JSON structure in {}/package.json: {}", package_json_dir.display(), expectation)
@@ -46,16 +62,63 @@ quick_error!{
             description("The given package information was traversed already")
             from()
         }
+        ReadDirectory(p: PathBuf, err: io::Error) {
+            description("A directory could not be read while searching for packages")
+            display("Failed to read directory '{}'", p.display())
+            cause(err)
+        }
+        ProjectRegistry(p: PathBuf, err: io::Error) {
+            description("The registry of projects using the repository could not be accessed")
+            display("Failed to access project registry at '{}'", p.display())
+            cause(err)
+        }
+        NoRegisteredProjects(repo: PathBuf) {
+            description("No project is registered as user of the repository")
+            display("No project is registered as user of '{}', which would make all of its packages unused",
+                    repo.display())
+        }
+        ContentMismatch(p: PathBuf, reference: PathBuf) {
+            description("A package differs from another copy of the same name and version")
+            display("The contents of '{}' differ from the ones of the same package version at '{}'",
+                    p.display(), reference.display())
+        }
+        UnsatisfiedPeerDependency(p: PathBuf, name: String, version_req: String) {
+            description("No version of a peer dependency within the repository satisfies its requirement")
+            display("No version of peer dependency '{}' matching '{}' of '{}' is available in the repository",
+                    name, version_req, p.display())
+        }
+        UnsatisfiedDependency(p: PathBuf, name: String, version_req: String) {
+            description("No version of a dependency within the repository satisfies its requirement")
+            display("No version of dependency '{}' matching '{}' of '{}' is available in the repository",
+                    name, version_req, p.display())
+        }
+        ContentHash(p: PathBuf, err: io::Error) {
+            description("The content hash of a package could not be computed or stored")
+            display("Failed to handle content hash of '{}'", p.display())
+            cause(err)
+        }
         DecodeJson(p: PathBuf, err: serde_json::Error) {
             description("The package.json could not be parsed as JSON")
             display("Failed to parse '{}'", p.display())
             context(p: DecodePackageFile<'a>, err: serde_json::Error) -> (p.0.to_path_buf(), err)
             cause(err)
         }
-        Visitor(p: PathBuf, err: Box<StdError>) {
+        Lockfile(p: PathBuf, expectation: String) {
+            description("A lockfile could not be read, or the data structure within it was unexpected")
+            display("Invalid lockfile '{}': {}", p.display(), expectation)
+        }
+        InvalidPlan(reason: String) {
+            description("A serialized plan could not be understood")
+            display("Invalid plan: {}", reason)
+        }
+        StalePlan(p: PathBuf, expectation: String) {
+            description("The filesystem does not match the assumptions of a plan anymore")
+            display("The plan does not match '{}' anymore: {}", p.display(), expectation)
+        }
+        Visitor(p: PathBuf, err: Box<dyn StdError>) {
             description("The visitor produced an error when changing")
             display("An error occurred: {}", err)
-            context(p: VisitorContext<'a>, err: Box<StdError>) -> (p.0.to_path_buf(), err)
+            context(p: VisitorContext<'a>, err: Box<dyn StdError>) -> (p.0.to_path_buf(), err)
             cause(&**err)
         }
     }
@@ -77,6 +140,50 @@ pub enum Instruction<'a> {
         this_directory: &'a Path,
         symlink_destination: &'a Path,
     },
+    /// Replace the symbolic link at `this_symlink` with a copy of the package directory in the
+    /// repository at `from_here`, undoing a previous `MoveAndSymlink` or `ReplaceWithSymlink`.
+    CopyBackFromRepo {
+        this_symlink: &'a Path,
+        from_here: &'a Path,
+    },
+    /// Remove the package directory `this_directory` from the repository, as no registered
+    /// project uses it anymore.
+    RemoveFromRepo { this_directory: &'a Path },
+    /// Move the package directory `from_here` to `to_here`, both of which are located in the
+    /// repository.
+    MoveWithinRepo {
+        from_here: &'a Path,
+        to_here: &'a Path,
+    },
+    /// Remove the symbolic link at `this_symlink`.
+    RemoveSymlink { this_symlink: &'a Path },
+    /// Change the existing symbolic link at `this_symlink` to point to `symlink_destination`,
+    /// which is a better suited version of the package it currently points to.
+    RetargetSymlink {
+        this_symlink: &'a Path,
+        symlink_destination: &'a Path,
+    },
+    /// Create a symbolic link at `this_symlink` which points to `symlink_destination`, along with
+    /// the directory containing it. Used to provide packages within the repository with their
+    /// peer dependencies.
+    CreateSymlink {
+        this_symlink: &'a Path,
+        symlink_destination: &'a Path,
+    },
+    /// Make every file within the directory `to_here` a hard link to the file at the same relative
+    /// path within `from_here`, creating `to_here` and all files and directories it lacks.
+    /// Nested `node_modules` directories are left alone, and files are copied instead if both
+    /// directories are on different devices.
+    HardlinkFiles {
+        from_here: &'a Path,
+        to_here: &'a Path,
+    },
+    /// As `HardlinkFiles`, but each file is made a copy-on-write clone of the one within
+    /// `from_here`, which falls back to a hard link if the filesystem does not support clones.
+    CloneFiles {
+        from_here: &'a Path,
+        to_here: &'a Path,
+    },
 }
 
 /// An version of Instruction which can be fully owned, as all fields are the owned version of their
@@ -92,6 +199,269 @@ pub enum InstructionOwned {
         this_directory: PathBuf,
         symlink_destination: PathBuf,
     },
+    CopyBackFromRepo {
+        this_symlink: PathBuf,
+        from_here: PathBuf,
+    },
+    RemoveFromRepo { this_directory: PathBuf },
+    MoveWithinRepo {
+        from_here: PathBuf,
+        to_here: PathBuf,
+    },
+    RemoveSymlink { this_symlink: PathBuf },
+    RetargetSymlink {
+        this_symlink: PathBuf,
+        symlink_destination: PathBuf,
+    },
+    CreateSymlink {
+        this_symlink: PathBuf,
+        symlink_destination: PathBuf,
+    },
+    HardlinkFiles {
+        from_here: PathBuf,
+        to_here: PathBuf,
+    },
+    CloneFiles {
+        from_here: PathBuf,
+        to_here: PathBuf,
+    },
+}
+
+impl<'a> Instruction<'a> {
+    /// Returns the name of this kind of instruction along with all of its paths, each with the name
+    /// of the field it is stored in.
+    pub fn fields(&self) -> (&'static str, Vec<(&'static str, &'a Path)>) {
+        match *self {
+            Instruction::MoveAndSymlink { from_here, to_here, symlink_destination } => {
+                ("MoveAndSymlink",
+                 vec![("from_here", from_here), ("to_here", to_here), ("symlink_destination", symlink_destination)])
+            }
+            Instruction::ReplaceWithSymlink { this_directory, symlink_destination } => {
+                ("ReplaceWithSymlink",
+                 vec![("this_directory", this_directory), ("symlink_destination", symlink_destination)])
+            }
+            Instruction::CopyBackFromRepo { this_symlink, from_here } => {
+                ("CopyBackFromRepo", vec![("this_symlink", this_symlink), ("from_here", from_here)])
+            }
+            Instruction::RemoveFromRepo { this_directory } => {
+                ("RemoveFromRepo", vec![("this_directory", this_directory)])
+            }
+            Instruction::MoveWithinRepo { from_here, to_here } => {
+                ("MoveWithinRepo", vec![("from_here", from_here), ("to_here", to_here)])
+            }
+            Instruction::RemoveSymlink { this_symlink } => ("RemoveSymlink", vec![("this_symlink", this_symlink)]),
+            Instruction::RetargetSymlink { this_symlink, symlink_destination } => {
+                ("RetargetSymlink",
+                 vec![("this_symlink", this_symlink), ("symlink_destination", symlink_destination)])
+            }
+            Instruction::CreateSymlink { this_symlink, symlink_destination } => {
+                ("CreateSymlink",
+                 vec![("this_symlink", this_symlink), ("symlink_destination", symlink_destination)])
+            }
+            Instruction::HardlinkFiles { from_here, to_here } => {
+                ("HardlinkFiles", vec![("from_here", from_here), ("to_here", to_here)])
+            }
+            Instruction::CloneFiles { from_here, to_here } => {
+                ("CloneFiles", vec![("from_here", from_here), ("to_here", to_here)])
+            }
+        }
+    }
+
+    /// Returns a JSON object with the kind of instruction in the `type` field, and one field per
+    /// path as returned by `fields()`. Paths which are not valid UTF-8 are stored as array of
+    /// their bytes.
+    pub fn to_json(&self) -> Value {
+        let (kind, paths) = self.fields();
+        let mut m = Map::new();
+        m.insert(String::from("type"), Value::String(kind.to_owned()));
+        for (name, path) in paths {
+            m.insert(name.to_owned(), path_to_json(path));
+        }
+        Value::Object(m)
+    }
+}
+
+impl<'a> fmt::Display for Instruction<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::MoveAndSymlink { from_here, to_here, symlink_destination } => {
+                write!(f,
+                       "move '{}' to '{}' and link it to '{}'",
+                       from_here.display(),
+                       to_here.display(),
+                       symlink_destination.display())
+            }
+            Instruction::ReplaceWithSymlink { this_directory, symlink_destination } => {
+                write!(f,
+                       "replace '{}' with a link to '{}'",
+                       this_directory.display(),
+                       symlink_destination.display())
+            }
+            Instruction::CopyBackFromRepo { this_symlink, from_here } => {
+                write!(f,
+                       "replace link '{}' with a copy of '{}'",
+                       this_symlink.display(),
+                       from_here.display())
+            }
+            Instruction::RemoveFromRepo { this_directory } => write!(f, "remove '{}'", this_directory.display()),
+            Instruction::MoveWithinRepo { from_here, to_here } => {
+                write!(f, "move '{}' to '{}'", from_here.display(), to_here.display())
+            }
+            Instruction::RemoveSymlink { this_symlink } => write!(f, "remove link '{}'", this_symlink.display()),
+            Instruction::RetargetSymlink { this_symlink, symlink_destination } => {
+                write!(f,
+                       "change link '{}' to point to '{}'",
+                       this_symlink.display(),
+                       symlink_destination.display())
+            }
+            Instruction::CreateSymlink { this_symlink, symlink_destination } => {
+                write!(f,
+                       "create link '{}' pointing to '{}'",
+                       this_symlink.display(),
+                       symlink_destination.display())
+            }
+            Instruction::HardlinkFiles { from_here, to_here } => {
+                write!(f,
+                       "hard link the files of '{}' to the ones in '{}'",
+                       to_here.display(),
+                       from_here.display())
+            }
+            Instruction::CloneFiles { from_here, to_here } => {
+                write!(f,
+                       "clone the files of '{}' from the ones in '{}'",
+                       to_here.display(),
+                       from_here.display())
+            }
+        }
+    }
+}
+
+impl InstructionOwned {
+    /// Returns a version of this instruction which borrows all of its fields.
+    pub fn as_instruction(&self) -> Instruction<'_> {
+        match *self {
+            InstructionOwned::MoveAndSymlink { ref from_here, ref to_here, ref symlink_destination } => {
+                Instruction::MoveAndSymlink {
+                    from_here,
+                    to_here,
+                    symlink_destination,
+                }
+            }
+            InstructionOwned::ReplaceWithSymlink { ref this_directory, ref symlink_destination } => {
+                Instruction::ReplaceWithSymlink {
+                    this_directory,
+                    symlink_destination,
+                }
+            }
+            InstructionOwned::CopyBackFromRepo { ref this_symlink, ref from_here } => {
+                Instruction::CopyBackFromRepo {
+                    this_symlink,
+                    from_here,
+                }
+            }
+            InstructionOwned::RemoveFromRepo { ref this_directory } => Instruction::RemoveFromRepo { this_directory },
+            InstructionOwned::MoveWithinRepo { ref from_here, ref to_here } => {
+                Instruction::MoveWithinRepo { from_here, to_here }
+            }
+            InstructionOwned::RemoveSymlink { ref this_symlink } => Instruction::RemoveSymlink { this_symlink },
+            InstructionOwned::RetargetSymlink { ref this_symlink, ref symlink_destination } => {
+                Instruction::RetargetSymlink {
+                    this_symlink,
+                    symlink_destination,
+                }
+            }
+            InstructionOwned::CreateSymlink { ref this_symlink, ref symlink_destination } => {
+                Instruction::CreateSymlink {
+                    this_symlink,
+                    symlink_destination,
+                }
+            }
+            InstructionOwned::HardlinkFiles { ref from_here, ref to_here } => {
+                Instruction::HardlinkFiles { from_here, to_here }
+            }
+            InstructionOwned::CloneFiles { ref from_here, ref to_here } => {
+                Instruction::CloneFiles { from_here, to_here }
+            }
+        }
+    }
+
+    /// Parse an instruction from a JSON object as produced by `Instruction::to_json()`.
+    pub fn from_json(v: &Value) -> Result<InstructionOwned, Error> {
+        let m = match *v {
+            Value::Object(ref m) => m,
+            _ => return Err(Error::InvalidPlan(String::from("An instruction must be an object"))),
+        };
+        let path = |field: &str| {
+            m.get(field)
+                .and_then(path_from_json)
+                .ok_or_else(|| Error::InvalidPlan(format!("Instruction field '{}' must be a path", field)))
+        };
+        Ok(match m.get("type") {
+            Some(Value::String(kind)) => {
+                match kind.as_str() {
+                    "MoveAndSymlink" => {
+                        InstructionOwned::MoveAndSymlink {
+                            from_here: path("from_here")?,
+                            to_here: path("to_here")?,
+                            symlink_destination: path("symlink_destination")?,
+                        }
+                    }
+                    "ReplaceWithSymlink" => {
+                        InstructionOwned::ReplaceWithSymlink {
+                            this_directory: path("this_directory")?,
+                            symlink_destination: path("symlink_destination")?,
+                        }
+                    }
+                    "CopyBackFromRepo" => {
+                        InstructionOwned::CopyBackFromRepo {
+                            this_symlink: path("this_symlink")?,
+                            from_here: path("from_here")?,
+                        }
+                    }
+                    "RemoveFromRepo" => InstructionOwned::RemoveFromRepo { this_directory: path("this_directory")? },
+                    "MoveWithinRepo" => {
+                        InstructionOwned::MoveWithinRepo {
+                            from_here: path("from_here")?,
+                            to_here: path("to_here")?,
+                        }
+                    }
+                    "RemoveSymlink" => InstructionOwned::RemoveSymlink { this_symlink: path("this_symlink")? },
+                    "RetargetSymlink" => {
+                        InstructionOwned::RetargetSymlink {
+                            this_symlink: path("this_symlink")?,
+                            symlink_destination: path("symlink_destination")?,
+                        }
+                    }
+                    "CreateSymlink" => {
+                        InstructionOwned::CreateSymlink {
+                            this_symlink: path("this_symlink")?,
+                            symlink_destination: path("symlink_destination")?,
+                        }
+                    }
+                    "HardlinkFiles" => {
+                        InstructionOwned::HardlinkFiles {
+                            from_here: path("from_here")?,
+                            to_here: path("to_here")?,
+                        }
+                    }
+                    "CloneFiles" => {
+                        InstructionOwned::CloneFiles {
+                            from_here: path("from_here")?,
+                            to_here: path("to_here")?,
+                        }
+                    }
+                    _ => return Err(Error::InvalidPlan(format!("Unknown instruction type '{}'", kind))),
+                }
+            }
+            _ => return Err(Error::InvalidPlan(String::from("Instruction field 'type' must be a string"))),
+        })
+    }
+}
+
+impl fmt::Display for InstructionOwned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_instruction().fmt(f)
+    }
 }
 
 impl<'a> From<Instruction<'a>> for InstructionOwned {
@@ -110,6 +480,48 @@ impl<'a> From<Instruction<'a>> for InstructionOwned {
                     symlink_destination: symlink_destination.to_owned(),
                 }
             }
+            Instruction::CopyBackFromRepo { this_symlink, from_here } => {
+                InstructionOwned::CopyBackFromRepo {
+                    this_symlink: this_symlink.to_owned(),
+                    from_here: from_here.to_owned(),
+                }
+            }
+            Instruction::RemoveFromRepo { this_directory } => {
+                InstructionOwned::RemoveFromRepo { this_directory: this_directory.to_owned() }
+            }
+            Instruction::MoveWithinRepo { from_here, to_here } => {
+                InstructionOwned::MoveWithinRepo {
+                    from_here: from_here.to_owned(),
+                    to_here: to_here.to_owned(),
+                }
+            }
+            Instruction::RemoveSymlink { this_symlink } => {
+                InstructionOwned::RemoveSymlink { this_symlink: this_symlink.to_owned() }
+            }
+            Instruction::RetargetSymlink { this_symlink, symlink_destination } => {
+                InstructionOwned::RetargetSymlink {
+                    this_symlink: this_symlink.to_owned(),
+                    symlink_destination: symlink_destination.to_owned(),
+                }
+            }
+            Instruction::CreateSymlink { this_symlink, symlink_destination } => {
+                InstructionOwned::CreateSymlink {
+                    this_symlink: this_symlink.to_owned(),
+                    symlink_destination: symlink_destination.to_owned(),
+                }
+            }
+            Instruction::HardlinkFiles { from_here, to_here } => {
+                InstructionOwned::HardlinkFiles {
+                    from_here: from_here.to_owned(),
+                    to_here: to_here.to_owned(),
+                }
+            }
+            Instruction::CloneFiles { from_here, to_here } => {
+                InstructionOwned::CloneFiles {
+                    from_here: from_here.to_owned(),
+                    to_here: to_here.to_owned(),
+                }
+            }
         }
     }
 }
@@ -120,26 +532,79 @@ pub trait Visitor {
     /// Called whenever the package identified by `package` could be processed. The exact
     /// problem is stated in `err`.
     fn error(&mut self, package: &PackageInfo, err: &Error);
+    /// Called whenever something about the package identified by `package` was unexpected, but
+    /// didn't prevent it from being processed, like an optional dependency which couldn't be
+    /// parsed. Unlike errors, warnings are not part of the result. Does nothing by default.
+    fn warning(&mut self, _package: &PackageInfo, _err: &Error) {}
     /// Called with an instruction on what to do next. Must never panic, and is expected to keep
     /// all error handling internal.
     fn change(&mut self, action: Instruction) -> Result<(), Self::Error>;
 }
 
-#[derive(Hash, Eq, PartialEq)]
+/// A package version. Unlike `Version`, versions which only differ in their build metadata are
+/// different packages, as they are located in different directories within the repository.
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd)]
 struct PackageKey {
     name: String,
     version: Version,
+    build: Vec<Identifier>,
+}
+
+impl PackageKey {
+    fn new(name: String, version: Version) -> PackageKey {
+        PackageKey {
+            name,
+            build: version.build.clone(),
+            version,
+        }
+    }
+}
+
+/// The way a package depends on another one, named after the key within package.json it is
+/// declared in.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+enum DependencyKind {
+    /// Declared in `dependencies`, and required at runtime.
+    Regular,
+    /// Declared in `devDependencies`, and only required to develop the package itself.
+    Dev,
+    /// Declared in `optionalDependencies`, and used at runtime if it could be installed.
+    Optional,
+    /// Declared in `peerDependencies`, and expected to be provided by the dependent of the package.
+    Peer,
 }
 
-#[derive(Hash, Eq, PartialEq)]
+impl DependencyKind {
+    const ALL: &'static [DependencyKind] =
+        &[DependencyKind::Regular, DependencyKind::Dev, DependencyKind::Optional, DependencyKind::Peer];
+
+    /// Returns the key within package.json dependencies of this kind are declared in.
+    fn key(self) -> &'static str {
+        match self {
+            DependencyKind::Regular => "dependencies",
+            DependencyKind::Dev => "devDependencies",
+            DependencyKind::Optional => "optionalDependencies",
+            DependencyKind::Peer => "peerDependencies",
+        }
+    }
+}
+
+/// A dependency of a package. The field order makes dependencies of the same name appear in the
+/// order of their kind.
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd)]
 struct PackageDependency {
     name: String,
-    version_req: String,
+    kind: DependencyKind,
+    /// The normalized `DependencySpec`.
+    spec: String,
 }
 
 struct PackageDependencies {
-    package_info: PackageInfo,
-    deps: HashSet<PackageDependency>,
+    /// All directories containing a copy of the package, in the order they were found.
+    locations: Vec<PackageInfo>,
+    platform_specific: bool,
+    /// All dependencies of the package, of every kind.
+    deps: BTreeSet<PackageDependency>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -151,57 +616,394 @@ pub struct PackageInfo {
 }
 
 impl PackageInfo {
+    /// The name of the package as derived from its directory, which includes the scope for
+    /// scoped packages, like `@scope/name`.
     pub fn name(&self) -> &OsStr {
-        self.directory.file_name().unwrap()
+        let scope_dir = self.directory.parent();
+        match scope_dir.and_then(|d| d.file_name()).and_then(|s| s.to_str()) {
+            Some(scope) if scope.starts_with('@') => {
+                scope_dir.and_then(|d| d.parent())
+                    .and_then(|base| self.directory.strip_prefix(base).ok())
+                    .map(|n| n.as_os_str())
+                    .unwrap_or_else(|| self.directory.file_name().unwrap())
+            }
+            _ => self.directory.file_name().unwrap(),
+        }
+    }
+}
+
+/// Returns true if `name` is a valid npm package name, either plain like `name` or scoped like
+/// `@scope/name`, which can safely be used as relative path.
+pub(crate) fn is_valid_package_name(name: &str) -> bool {
+    fn is_valid_component(c: &str) -> bool {
+        !c.is_empty() && !c.starts_with('.') && !c.contains('\\') && !c.contains('\0')
+    }
+    let mut components = name.split('/');
+    match (components.next(), components.next(), components.next()) {
+        (Some(scope), Some(name), None) => {
+            scope.len() > 1 && scope.starts_with('@') && is_valid_component(&scope[1..]) &&
+            is_valid_component(name)
+        }
+        (Some(name), None, None) => !name.starts_with('@') && is_valid_component(name),
+        _ => false,
+    }
+}
+
+/// Returns the location of the package `name` at `version` within `repo`, which is
+/// `<repo>/<name>/<version>`, or `<repo>/@scope/<name>/<version>` for scoped packages.
+pub(crate) fn repo_destination(repo: &Path, name: &str, version: &Version) -> PathBuf {
+    name.split('/')
+        .fold(repo.to_path_buf(), |path, component| path.join(component))
+        .join(format!("{}", version))
+}
+
+/// Returns the directory within `repo` the package version `pi` is stored in.
+fn platform_destination(repo: &Path,
+                        pi: &PackageKey,
+                        pd: &PackageDependencies,
+                        options: &DeduplicationOptions)
+                        -> PathBuf {
+    let mut destination = options.repo_layout.package_directory(repo, &pi.name, &pi.version);
+    if pd.platform_specific {
+        destination.push(&options.platform);
+    }
+    destination
+}
+
+/// The failure to load a package.json, which unlike `Error` can be sent between threads.
+enum ManifestError {
+    Read(io::Error),
+    Decode(serde_json::Error),
+}
+
+/// Read and parse the package.json within `dir`, which is the part of handling a package that
+/// benefits from running in parallel.
+fn load_manifest(dir: &Path) -> Result<Value, ManifestError> {
+    let rd = fs::File::open(dir.join("package.json")).map_err(ManifestError::Read)?;
+    from_reader(rd).map_err(ManifestError::Decode)
+}
+
+pub(crate) fn read_package_json(p: &PackageInfo) -> std::result::Result<Map<String, Value>, Error> {
+    package_json(p, load_manifest(&p.directory))
+}
+
+fn package_json(p: &PackageInfo, manifest: Result<Value, ManifestError>) -> Result<Map<String, Value>, Error> {
+    let pjp = p.directory.join("package.json");
+    let manifest = match manifest {
+        Ok(manifest) => manifest,
+        Err(ManifestError::Read(err)) => return Err(err).context(ReadPackageFile(&pjp)).map_err(Into::into),
+        Err(ManifestError::Decode(err)) => return Err(err).context(DecodePackageFile(&pjp)).map_err(Into::into),
+    };
+    match manifest {
+        Value::Object(val) => Ok(val),
+        _ => {
+            Err(Error::JsonStructure(p.directory.clone(),
+                                     String::from("Top level was not an object")))
+        }
+    }
+}
+
+pub(crate) fn fetch_string(m: &Map<String, Value>, p: &PackageInfo, field_name: &str) -> Result<String, Error> {
+    m.get(field_name)
+        .and_then(|v| match *v {
+            Value::String(ref v) => Some(v.to_owned()),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            Error::JsonStructure(p.directory.clone(),
+                                 format!("'{}' key was not present, or its value was not a string",
+                                         field_name))
+        })
+}
+
+/// Determines how the destination of symbolic links into the repository is expressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SymlinkStyle {
+    /// The symbolic link contains the absolute path to the package within the repository.
+    #[default]
+    Absolute,
+    /// The symbolic link contains the path to the package within the repository relative to the
+    /// directory containing the link. This keeps links valid if project and repository are moved
+    /// or mounted elsewhere together.
+    Relative,
+}
+
+impl SymlinkStyle {
+    /// Returns the path to put into a symbolic link located at `link` to make it point to
+    /// `destination`.
+    pub fn destination(&self, link: &Path, destination: &Path) -> PathBuf {
+        match *self {
+            SymlinkStyle::Absolute => destination.to_owned(),
+            SymlinkStyle::Relative => relative_to(link.parent().unwrap_or_else(|| Path::new(".")), destination),
+        }
+    }
+}
+
+/// Determines how copies of a package are made to share the package within the repository.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum LinkMode {
+    /// Each copy is replaced by a symbolic link to the package directory within the repository.
+    #[default]
+    Symlink,
+    /// Each copy remains a real directory, but every file within it is replaced by a hard link to
+    /// the respective file within the repository, which keeps the way Node resolves modules
+    /// unchanged. Files are copied if the project and the repository are on different devices.
+    Hardlink,
+    /// As `Hardlink`, but every file is a copy-on-write clone, which shares disk blocks without
+    /// allowing modifications of one copy to affect the others. Falls back to hard links on
+    /// filesystems without support for clones. As clones can't be told apart from copies, all
+    /// copies are cloned again whenever the project is deduplicated.
+    Reflink,
+}
+
+/// Returns the amount of `node_modules` directories between the package `p` and its root directory.
+fn depth(p: &PackageInfo) -> usize {
+    p.directory
+        .strip_prefix(&p.root_directory)
+        .map(|rel| rel.components().filter(|c| c.as_os_str() == "node_modules").count())
+        .unwrap_or(0)
+}
+
+/// Returns the indices of `items` in ascending order of their key, except that items whose
+/// directory is nested within the directory of another item come after that item.
+fn parents_first<K: Ord>(items: &[(K, &Path)]) -> Vec<usize> {
+    let index: HashMap<&Path, usize> = items.iter().enumerate().map(|(i, &(_, p))| (p, i)).collect();
+    let mut children = vec![Vec::new(); items.len()];
+    let mut ready = BTreeSet::new();
+    for (i, &(ref key, p)) in items.iter().enumerate() {
+        match p.ancestors().skip(1).find_map(|a| index.get(a)) {
+            Some(&parent) => children[parent].push(i),
+            None => {
+                ready.insert((key, i));
+            }
+        }
+    }
+    let mut order = Vec::with_capacity(items.len());
+    while let Some((_, i)) = ready.pop_first() {
+        order.push(i);
+        for &child in &children[i] {
+            ready.insert((&items[child].0, child));
+        }
+    }
+    order
+}
+
+/// Returns the location `p` will physically have once the package directories in `relocations`
+/// were moved to or replaced by their respective repository location.
+fn relocated(relocations: &HashMap<&Path, PathBuf>, p: &Path) -> PathBuf {
+    let parent = p.parent().unwrap_or(p);
+    parent.ancestors()
+        .find_map(|a| relocations.get(a).map(|to| to.join(p.strip_prefix(a).expect("ancestor to be a prefix"))))
+        .unwrap_or_else(|| p.to_owned())
+}
+
+/// A copy of a package version along with its directory within the repository.
+type Placement<'a> = (&'a PackageKey, &'a PackageDependencies, &'a PackageInfo, PathBuf);
+
+/// Returns true if every package nested within `copy`, as listed in `nested`, is found at the
+/// same place within `reference`, with the same name and version.
+fn nested_packages_match(copy: &Path, reference: &Path, nested: &[(&PackageKey, &Path)]) -> bool {
+    nested.iter()
+        .filter_map(|&(key, dir)| dir.strip_prefix(copy).ok().map(|rel| (key, rel)))
+        .filter(|&(_, rel)| rel.as_os_str() != "")
+        .all(|(key, rel)| {
+            let info = PackageInfo {
+                directory: reference.join(rel),
+                root_directory: reference.to_owned(),
+            };
+            read_package_json(&info).ok().is_some_and(|pj| {
+                let name = fetch_string(&pj, &info, "name").ok();
+                let version = fetch_string(&pj, &info, "version").ok().and_then(|v| Version::parse(&v).ok());
+                name.zip(version).map(|(n, v)| PackageKey::new(n, v)).as_ref() == Some(key)
+            })
+        })
+}
+
+/// Move each copy in `placements` whose nested packages differ from the ones of the first copy,
+/// or the package within the repository, after all other placements, innermost copies first.
+/// Replacing such a copy with a link would otherwise discard its nested packages before they
+/// were placed into the repository.
+fn nested_packages_first(placements: Vec<Placement>) -> Vec<Placement> {
+    let nested: Vec<_> = placements.iter().map(|&(pi, _, p, _)| (pi, p.directory.as_path())).collect();
+    let mut references = HashMap::new();
+    let mut deferred = Vec::new();
+    for (i, &(pi, _, p, ref destination)) in placements.iter().enumerate() {
+        if p.directory.symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(false) {
+            continue;
+        }
+        let reference = references.entry(pi).or_insert_with(|| if destination.is_dir() {
+            destination.clone()
+        } else {
+            p.directory.clone()
+        });
+        if *reference != p.directory && !nested_packages_match(&p.directory, reference, &nested) {
+            deferred.push(i);
+        }
+    }
+    if deferred.is_empty() {
+        return placements;
+    }
+    let mut reordered: Vec<_> = placements.iter()
+        .enumerate()
+        .filter(|&(i, _)| !deferred.contains(&i))
+        .map(|(_, placement)| placement.clone())
+        .collect();
+    reordered.extend(deferred.iter().rev().map(|&i| placements[i].clone()));
+    reordered
+}
+
+/// Content hashes of package directories, computed at most once per directory.
+#[derive(Default)]
+struct HashCache(HashMap<PathBuf, String>);
+
+impl HashCache {
+    /// Returns the content hash of `dir`. If `repo` is set, the hash recorded for the package
+    /// within the repository is used, if there is one.
+    fn get(&mut self, dir: &Path, repo: Option<&Path>) -> Result<String, Error> {
+        if let Some(hash) = self.0.get(dir) {
+            return Ok(hash.clone());
+        }
+        let recorded = match repo {
+            Some(repo) => read_stored_hash(repo, dir).map_err(|err| Error::ContentHash(dir.to_owned(), err))?,
+            None => None,
+        };
+        let hash = match recorded {
+            Some(hash) => hash,
+            None => content_hash(dir).map_err(|err| Error::ContentHash(dir.to_owned(), err))?,
+        };
+        self.0.insert(dir.to_owned(), hash.clone());
+        Ok(hash)
+    }
+
+    /// Compute the content hashes of all `dirs` on up to `threads` threads. Failures are left to
+    /// be reported by `get(...)`.
+    fn precompute(&mut self, dirs: &[&Path], threads: usize) {
+        let hashes = parallel_map(dirs, threads, |dir| content_hash(dir).ok());
+        for (dir, hash) in dirs.iter().zip(hashes) {
+            if let Some(hash) = hash {
+                self.0.insert(dir.to_path_buf(), hash);
+            }
+        }
+    }
+}
+
+/// Options to control how `deduplicate_into_with(...)` computes its instructions.
+#[derive(Clone, Debug)]
+pub struct DeduplicationOptions {
+    pub symlink_style: SymlinkStyle,
+    pub link_mode: LinkMode,
+    /// Determines which packages are stored in a `<platform>` directory below their package
+    /// directory within the repository.
+    pub platform_segment: PlatformSegment,
+    /// The name of the platform to use as last path component of platform specific packages.
+    /// Defaults to the result of `current_platform()`.
+    pub platform: String,
+    /// The amount of threads to read package.json files with. Values below 2 read them on the
+    /// calling thread, which is the default. The visitor is always called from the calling thread,
+    /// and in the same order as without threads.
+    pub threads: usize,
+    /// If true, a copy of a package is only replaced with a link into the repository if its
+    /// `content_hash(...)` matches the one of the package in the repository. Otherwise, an
+    /// `Error::ContentMismatch` is reported for it. Copies are hashed on `threads` threads.
+    /// Defaults to false, which trusts all copies of a package version to be the same.
+    pub compare_contents: bool,
+    /// Determines where packages are stored within the repository. Defaults to
+    /// `NameVersionLayout`. The maintenance of the repository, like `gc(...)` and
+    /// `verify_repo(...)`, only sees packages stored in the default layout.
+    pub repo_layout: Arc<dyn RepoLayout>,
+    /// If true, the project of each package is registered as user of the repository before any
+    /// instruction is handed out, which protects the packages it links to from `gc(...)`. The
+    /// project is the directory containing the `node_modules` directory which is the
+    /// `root_directory` of a package. Defaults to false, as only visitors which apply the
+    /// instructions need this, while plans and dry runs must not leave traces in the repository.
+    pub register_projects: bool,
+}
+
+impl Default for DeduplicationOptions {
+    fn default() -> Self {
+        DeduplicationOptions {
+            symlink_style: SymlinkStyle::default(),
+            link_mode: LinkMode::default(),
+            platform_segment: PlatformSegment::default(),
+            platform: current_platform(),
+            threads: 1,
+            compare_contents: false,
+            repo_layout: Arc::new(NameVersionLayout),
+            register_projects: false,
+        }
     }
 }
 
 /// Iterate `items` and read all package.json files contained therein to collect enough information
 /// to compute all changes required to sym-link or update the respective packages in `repo`.
 /// `visitor` will be called whenever something goes wrong, or whenever there is something to do.
+///
+/// The first copy of each package version is moved into the repository unless it is there
+/// already, and every copy is replaced with a symbolic link to it. With `LinkMode::Hardlink`, the
+/// files of the first copy are hard linked into the repository instead, and the files of every
+/// other copy are replaced with hard links to the ones within the repository. `LinkMode::Reflink`
+/// does the same with clones.
+///
+/// Instructions for packages come first, ordered by package name, version, the amount of
+/// `node_modules` directories they are nested in, and finally their path. A package nested within
+/// another package is always handed out after the latter, as it has to be applied once its parent
+/// is located in the repository. A copy which is replaced by a symbolic link shares the nested
+/// packages of the package within the repository from then on. Thus if any of its nested packages
+/// differs from the one at the same place within the first copy, the copy is replaced only after
+/// all of its nested packages were placed into the repository, as last of all packages and
+/// innermost copies first. Instructions to link the dependencies of packages within the
+/// repository follow, ordered by package name, version and the name of the dependency.
+/// Instructions to retarget links to dependencies come last, in the same order as packages, and by
+/// name of the dependency.
+///
+/// A package within the repository is resolved from its real path by Node, which doesn't see the
+/// project's `node_modules` anymore. Thus each of its peer dependencies is linked to the highest
+/// version within the repository satisfying it, from the `node_modules` directory of the package.
+/// An `Error::UnsatisfiedPeerDependency` is reported for each peer without such a version. If the
+/// `RepoLayout` links dependencies next to each package, like `PnpmLayout`, all other dependencies
+/// but development ones are linked there as well, and an `Error::UnsatisfiedDependency` is
+/// reported for each of them without a version in the repository. Missing optional dependencies
+/// are only reported as warning, like dependencies whose name is no valid package name, which are
+/// skipped.
 pub fn deduplicate_into<'a, P, I, V, E>(repo: P, items: I, visitor: &mut V) -> Result<(), Vec<Error>>
     where P: AsRef<Path>,
           I: IntoIterator<Item = &'a PackageInfo>,
           E: StdError + 'static,
           V: Visitor<Error = E>
 {
-    fn read_package_json(p: &PackageInfo) -> std::result::Result<Map<String, Value>, Error> {
-        let pjp = p.directory.join("package.json");
-        let rd = try!(fs::File::open(&pjp).context(ReadPackageFile(&pjp)));
-        match try!(from_reader(rd).context(DecodePackageFile((&pjp)))) {
-            Value::Object(val) => Ok(val),
-            _ => {
-                Err(Error::JsonStructure(p.directory.clone(),
-                                         String::from("Top level was not an object")))
-            }
-        }
-    }
-
-    fn fetch_string(m: &Map<String, Value>, p: &PackageInfo, field_name: &str) -> Result<String, Error> {
-        m.get(field_name)
-            .and_then(|v| match *v {
-                Value::String(ref v) => Some(v.to_owned()),
-                _ => None,
-            })
-            .ok_or_else(|| {
-                Error::JsonStructure(p.directory.clone(),
-                                     format!("'{}' key was not present, or its value was not a string",
-                                             field_name))
-            })
-    }
+    deduplicate_into_with(repo, items, &DeduplicationOptions::default(), visitor)
+}
 
-    fn handle_error<E>(p: &PackageInfo, errs: &mut Vec<Error>, err: Error, v: &mut Visitor<Error = E>) {
+/// As `deduplicate_into(...)`, but with `options` to control the instructions produced.
+pub fn deduplicate_into_with<'a, P, I, V, E>(repo: P,
+                                             items: I,
+                                             options: &DeduplicationOptions,
+                                             visitor: &mut V)
+                                             -> Result<(), Vec<Error>>
+    where P: AsRef<Path>,
+          I: IntoIterator<Item = &'a PackageInfo>,
+          E: StdError + 'static,
+          V: Visitor<Error = E>
+{
+    fn handle_error<E>(p: &PackageInfo, errs: &mut Vec<Error>, err: Error, v: &mut dyn Visitor<Error = E>) {
         v.error(p, &err);
         errs.push(err);
     }
 
     fn handle_package<E>(p: &PackageInfo,
+                         manifest: Result<Value, ManifestError>,
+                         options: &DeduplicationOptions,
                          errors: &mut Vec<Error>,
-                         deps: &mut HashMap<PackageKey, PackageDependencies>,
-                         visitor: &mut Visitor<Error = E>) {
-        match read_package_json(p).and_then(|pj| {
+                         deps: &mut BTreeMap<PackageKey, PackageDependencies>,
+                         visitor: &mut dyn Visitor<Error = E>) {
+        match package_json(p, manifest).and_then(|pj| {
             fetch_string(&pj, p, "version")
                 .and_then(|v| fetch_string(&pj, p, "name").map(|n| (v, n)))
+                .and_then(|(v, n)| if is_valid_package_name(&n) {
+                    Ok((v, n))
+                } else {
+                    Err(Error::InvalidPackageName(p.directory.clone(), n))
+                })
                 .and_then(|(v, n)| {
                     Version::parse(&v)
                         .context(PathAndVersion(&p.directory, &v))
@@ -210,54 +1012,73 @@ pub fn deduplicate_into<'a, P, I, V, E>(repo: P, items: I, visitor: &mut V) -> R
                 })
         }) {
             Ok((pj, semantic_version, name)) => {
-                let mut dep_info = match deps.entry(PackageKey {
-                    name: name,
-                    version: semantic_version,
-                }) {
+                let platform_specific = options.platform_segment
+                    .applies_to(&name, || is_native_package(&p.directory, &pj));
+                let dep_info = match deps.entry(PackageKey::new(name, semantic_version)) {
                     Entry::Vacant(e) => {
                         e.insert(PackageDependencies {
-                            package_info: p.clone(),
+                            locations: vec![p.clone()],
+                            platform_specific,
                             deps: Default::default(),
                         })
                     }
-                    Entry::Occupied(e) => {
-                        if e.get().package_info == *p {
+                    // Copies share their dependencies, which were handled with the first one already
+                    Entry::Occupied(mut e) => {
+                        if e.get().locations.contains(p) {
                             handle_error(p, errors, p.clone().into(), visitor)
+                        } else {
+                            e.get_mut().locations.push(p.clone());
                         }
                         return;
                     }
                 };
-                for dep_key in &["dependencies", "devDependencies"] {
-                    if let Some(deps) = pj.get(*dep_key) {
+                for &kind in DependencyKind::ALL {
+                    let dep_key = kind.key();
+                    // Optional dependencies may be missing, which is why problems with them are no errors
+                    let report = |err: Error, errors: &mut Vec<Error>, visitor: &mut dyn Visitor<Error = E>| {
+                        if kind == DependencyKind::Optional {
+                            visitor.warning(p, &err);
+                        } else {
+                            handle_error(p, errors, err, visitor);
+                        }
+                    };
+                    if let Some(deps) = pj.get(dep_key) {
                         match deps.as_object().ok_or_else(|| {
                             Error::JsonStructure(p.directory.to_owned(),
                                                  format!("Key {} was not an object", dep_key))
                         }) {
                             Ok(deps) => {
                                 for (dep_name, dep_version) in deps.iter() {
+                                    // Names are used as paths, and invalid ones could point anywhere
+                                    if !is_valid_package_name(dep_name) {
+                                        let err = Error::InvalidPackageName(p.directory.clone(), dep_name.to_owned());
+                                        visitor.warning(p, &err);
+                                        continue;
+                                    }
                                     let normalized_req = match dep_version.as_str()
                                         .ok_or_else(|| {
                                             Error::JsonStructure(p.directory.clone(),
                                                                  String::from("version of dependency was not a string"))
                                         })
                                         .and_then(|v| {
-                                            VersionReq::parse(v)
+                                            DependencySpec::parse(v)
                                                 .context(PathAndVersion(&p.directory, v))
                                                 .map_err(|err| err.into())
                                         }) {
                                         Ok(vr) => vr,
                                         Err(err) => {
-                                            handle_error(p, errors, err, visitor);
+                                            report(err, errors, visitor);
                                             continue;
                                         }
                                     };
                                     dep_info.deps.insert(PackageDependency {
                                         name: dep_name.to_owned(),
-                                        version_req: format!("{}", normalized_req),
+                                        kind,
+                                        spec: normalized_req.to_string(),
                                     });
                                 }
                             }
-                            Err(err) => handle_error(p, errors, err, visitor),
+                            Err(err) => report(err, errors, visitor),
                         }
                     }
                 }
@@ -269,29 +1090,275 @@ pub fn deduplicate_into<'a, P, I, V, E>(repo: P, items: I, visitor: &mut V) -> R
     }
 
     let mut errors = Vec::new();
-    let mut deps = HashMap::new();
-    for p in items {
-        handle_package(p, &mut errors, &mut deps, visitor);
+    let mut deps = BTreeMap::new();
+    if options.threads < 2 {
+        for p in items {
+            handle_package(p, load_manifest(&p.directory), options, &mut errors, &mut deps, visitor);
+        }
+    } else {
+        let items: Vec<_> = items.into_iter().collect();
+        let manifests = parallel_map(&items, options.threads, |p| load_manifest(&p.directory));
+        for (p, manifest) in items.into_iter().zip(manifests) {
+            handle_package(p, manifest, options, &mut errors, &mut deps, visitor);
+        }
+    }
+
+    if options.register_projects {
+        let mut projects = BTreeMap::new();
+        for p in deps.values().flat_map(|pd| &pd.locations) {
+            if p.root_directory.file_name().is_some_and(|n| n == "node_modules") {
+                projects.entry(p.root_directory.parent().unwrap_or(&p.root_directory)).or_insert(p);
+            }
+        }
+        for (project, p) in projects {
+            if let Err(err) = register_project(repo.as_ref(), project) {
+                handle_error(p, &mut errors, err, visitor);
+            }
+        }
+    }
+
+    let mut placements: Vec<_> = deps.iter()
+        .flat_map(|(pi, pd)| {
+            let destination = platform_destination(repo.as_ref(), pi, pd, options);
+            pd.locations.iter().map(move |p| (pi, pd, p, destination.clone()))
+        })
+        .collect();
+    let order = parents_first(&placements.iter()
+        .map(|&(pi, _, p, _)| {
+            ((pi.name.as_str(), &pi.version, depth(p), p.directory.as_path()), p.directory.as_path())
+        })
+        .collect::<Vec<_>>());
+    placements = order.into_iter().map(|i| placements[i].clone()).collect();
+    if options.link_mode == LinkMode::Symlink {
+        placements = nested_packages_first(placements);
     }
 
-    for (pi, pd) in deps {
-        let destination = repo.as_ref().join(&pi.name).join(format!("{}", &pi.version));
-        let p = &pd.package_info;
-        let instruction = if destination.is_dir() {
-            Instruction::ReplaceWithSymlink {
-                this_directory: p.directory.as_ref(),
-                symlink_destination: destination.as_ref(),
+    // Where each package directory will physically be located once its instruction was applied
+    let mut relocations = HashMap::new();
+    // The first copy of a package is moved into the repository, all others are replaced
+    let mut in_repo = HashSet::new();
+    // The directory whose contents will end up at the destination, if it's not there yet
+    let mut moved_copies = HashMap::new();
+    let mut hashes = HashCache::default();
+    if options.compare_contents && options.threads > 1 {
+        let compared: Vec<_> = placements.iter()
+            .filter(|&&(_, pd, p, ref destination)| {
+                let is_symlink = p.directory.symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(true);
+                !is_symlink && (pd.locations.len() > 1 || destination.is_dir())
+            })
+            .map(|&(_, _, p, _)| p.directory.as_path())
+            .collect();
+        hashes.precompute(&compared, options.threads);
+    }
+    for &(pi, _, p, ref destination) in &placements {
+        let is_symlink = p.directory.symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(false);
+        if is_symlink {
+            relocations.insert(p.directory.as_path(), absolute(&p.directory));
+            continue;
+        }
+        if options.link_mode == LinkMode::Hardlink &&
+           same_file(&p.directory.join("package.json"), &destination.join("package.json")) {
+            in_repo.insert(pi);
+            continue;
+        }
+        let link = relocated(&relocations, &p.directory);
+        let symlink_destination = options.symlink_style.destination(&link, destination);
+        let instruction = if !in_repo.insert(pi) || destination.is_dir() {
+            if options.compare_contents {
+                let (reference, stored) = match moved_copies.get(destination.as_path()) {
+                    Some(copy) => (*copy, None),
+                    None => (destination.as_path(), Some(repo.as_ref())),
+                };
+                let same = hashes.get(reference, stored)
+                    .and_then(|expected| hashes.get(&p.directory, None).map(|actual| expected == actual));
+                match same {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let err = Error::ContentMismatch(p.directory.clone(), reference.to_owned());
+                        handle_error(p, &mut errors, err, visitor);
+                        continue;
+                    }
+                    Err(err) => {
+                        handle_error(p, &mut errors, err, visitor);
+                        continue;
+                    }
+                }
+            }
+            match options.link_mode {
+                LinkMode::Symlink => {
+                    Instruction::ReplaceWithSymlink {
+                        this_directory: p.directory.as_ref(),
+                        symlink_destination: symlink_destination.as_ref(),
+                    }
+                }
+                LinkMode::Hardlink => {
+                    Instruction::HardlinkFiles {
+                        from_here: destination.as_ref(),
+                        to_here: p.directory.as_ref(),
+                    }
+                }
+                LinkMode::Reflink => {
+                    Instruction::CloneFiles {
+                        from_here: destination.as_ref(),
+                        to_here: p.directory.as_ref(),
+                    }
+                }
             }
         } else {
-            Instruction::MoveAndSymlink {
-                from_here: p.directory.as_ref(),
-                to_here: destination.as_ref(),
-                symlink_destination: destination.as_ref(),
+            moved_copies.insert(destination.as_path(), p.directory.as_path());
+            match options.link_mode {
+                LinkMode::Symlink => {
+                    Instruction::MoveAndSymlink {
+                        from_here: p.directory.as_ref(),
+                        to_here: destination.as_ref(),
+                        symlink_destination: symlink_destination.as_ref(),
+                    }
+                }
+                LinkMode::Hardlink => {
+                    Instruction::HardlinkFiles {
+                        from_here: p.directory.as_ref(),
+                        to_here: destination.as_ref(),
+                    }
+                }
+                LinkMode::Reflink => {
+                    Instruction::CloneFiles {
+                        from_here: p.directory.as_ref(),
+                        to_here: destination.as_ref(),
+                    }
+                }
             }
         };
-        if !p.directory.symlink_metadata().unwrap().file_type().is_symlink() {
-            visitor.change(instruction)
-                .map_err(|err| Error::Visitor(p.directory.clone(), Box::new(err)))
+        if options.link_mode == LinkMode::Symlink {
+            relocations.insert(p.directory.as_path(), destination.clone());
+        }
+        visitor.change(instruction)
+            .map_err(|err| Error::Visitor(p.directory.clone(), Box::new(err)))
+            .or_else(|err| {
+                handle_error(p, &mut errors, err, visitor);
+                Ok::<_, Error>(())
+            })
+            .ok();
+    }
+
+    // Provide packages within the repository with the dependencies they can't find in the
+    // project's `node_modules` anymore once a package is resolved from its real path
+    if options.link_mode == LinkMode::Symlink {
+        let layout = &*options.repo_layout;
+        for (pi, pd) in &deps {
+            let destination = platform_destination(repo.as_ref(), pi, pd, options);
+            if !(in_repo.contains(pi) || destination.is_dir()) {
+                continue;
+            }
+            // Without a directory for siblings, only peers are linked into the package's node_modules
+            let siblings = layout.dependency_directory(repo.as_ref(), &pi.name, &pi.version);
+            let links = siblings.clone().unwrap_or_else(|| destination.join("node_modules"));
+            let moved_copy = moved_copies.get(destination.as_path());
+            let mut linked = HashSet::new();
+            for dep in &pd.deps {
+                let provided = match dep.kind {
+                    DependencyKind::Peer => true,
+                    DependencyKind::Regular | DependencyKind::Optional => siblings.is_some(),
+                    DependencyKind::Dev => false,
+                };
+                if !provided || !linked.insert(dep.name.as_str()) {
+                    continue;
+                }
+                let spec = match DependencySpec::parse(&dep.spec) {
+                    Ok(spec) => spec,
+                    Err(_) => continue,
+                };
+                let (name, req) = match spec.registry_range(&dep.name) {
+                    Some(range) => range,
+                    None => continue,
+                };
+                let in_node_modules = |dir: &Path| dep.name.split('/').fold(dir.to_path_buf(), |l, c| l.join(c));
+                let link = in_node_modules(&links);
+                // Dependencies within the package's own node_modules take precedence
+                if link.symlink_metadata().is_ok() ||
+                   in_node_modules(&destination.join("node_modules")).symlink_metadata().is_ok() ||
+                   moved_copy.is_some_and(|c| in_node_modules(&c.join("node_modules")).exists()) {
+                    continue;
+                }
+                let best = deps.iter()
+                    .filter(|&(k, _)| k.name == name && in_repo.contains(k) && req.satisfies(&k.version))
+                    .map(|(k, _)| k.version.clone())
+                    .chain(best_repo_version(repo.as_ref(), layout, name, req))
+                    .max();
+                let dep_destination = match best {
+                    Some(version) => {
+                        let key = PackageKey::new(name.to_owned(), version);
+                        let mut dep_destination = layout.package_directory(repo.as_ref(), &key.name, &key.version);
+                        let platform_specific = match deps.get(&key) {
+                            Some(dep_pd) => dep_pd.platform_specific,
+                            None => dep_destination.join(&options.platform).is_dir(),
+                        };
+                        if platform_specific {
+                            dep_destination.push(&options.platform);
+                        }
+                        dep_destination
+                    }
+                    None => {
+                        let (name, spec) = (dep.name.clone(), dep.spec.clone());
+                        match dep.kind {
+                            DependencyKind::Peer => {
+                                let err = Error::UnsatisfiedPeerDependency(destination.clone(), name, spec);
+                                handle_error(&pd.locations[0], &mut errors, err, visitor);
+                            }
+                            DependencyKind::Optional => {
+                                let err = Error::UnsatisfiedDependency(destination.clone(), name, spec);
+                                visitor.warning(&pd.locations[0], &err);
+                            }
+                            _ => {
+                                let err = Error::UnsatisfiedDependency(destination.clone(), name, spec);
+                                handle_error(&pd.locations[0], &mut errors, err, visitor);
+                            }
+                        }
+                        continue;
+                    }
+                };
+                visitor.change(Instruction::CreateSymlink {
+                        this_symlink: &link,
+                        symlink_destination: &options.symlink_style.destination(&link, &dep_destination),
+                    })
+                    .map_err(|err| Error::Visitor(link.clone(), Box::new(err)))
+                    .or_else(|err| {
+                        handle_error(&pd.locations[0], &mut errors, err, visitor);
+                        Ok::<_, Error>(())
+                    })
+                    .ok();
+            }
+        }
+    }
+
+    // Point links to dependencies to the best version within the repository
+    for &(_, pd, p, _) in &placements {
+        // Peers are provided by the dependent, and optional dependencies are usually declared as
+        // regular ones as well, so each link is only retargeted once
+        let mut retargeted = HashSet::new();
+        for dep in &pd.deps {
+            if dep.kind == DependencyKind::Peer || !retargeted.insert(dep.name.as_str()) {
+                continue;
+            }
+            let spec = match DependencySpec::parse(&dep.spec) {
+                Ok(spec) => spec,
+                Err(_) => continue,
+            };
+            // Aliases are linked under their own name, but resolved by the name of their package
+            let (name, req) = match spec.registry_range(&dep.name) {
+                Some(range) => range,
+                None => continue,
+            };
+            let link = dep.name.split('/').fold(p.directory.join("node_modules"), |l, c| l.join(c));
+            let destination = match retarget_destination(&link, repo.as_ref(), &*options.repo_layout, name, req) {
+                Some(destination) => destination,
+                None => continue,
+            };
+            let symlink_destination = options.symlink_style.destination(&relocated(&relocations, &link), &destination);
+            visitor.change(Instruction::RetargetSymlink {
+                    this_symlink: &link,
+                    symlink_destination: &symlink_destination,
+                })
+                .map_err(|err| Error::Visitor(link.clone(), Box::new(err)))
                 .or_else(|err| {
                     handle_error(p, &mut errors, err, visitor);
                     Ok::<_, Error>(())
@@ -0,0 +1,555 @@
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+
+use dedup::{Error, Instruction, PackageInfo, Visitor};
+use hash::{content_hash, stored_hash_path_of, write_hash};
+use paths::same_file;
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum ExecutionError {
+        CreateDirectory(p: PathBuf, err: io::Error) {
+            description("A directory could not be created")
+            display("Failed to create directory '{}'", p.display())
+            cause(err)
+        }
+        Move(from: PathBuf, to: PathBuf, err: io::Error) {
+            description("A directory could not be moved")
+            display("Failed to move '{}' to '{}'", from.display(), to.display())
+            cause(err)
+        }
+        Copy(from: PathBuf, to: PathBuf, err: io::Error) {
+            description("A file or directory could not be copied")
+            display("Failed to copy '{}' to '{}'", from.display(), to.display())
+            cause(err)
+        }
+        Remove(p: PathBuf, err: io::Error) {
+            description("A directory or symbolic link could not be removed")
+            display("Failed to remove '{}'", p.display())
+            cause(err)
+        }
+        Symlink(p: PathBuf, destination: PathBuf, err: io::Error) {
+            description("A symbolic link could not be created")
+            display("Failed to create symlink at '{}' pointing to '{}'", p.display(), destination.display())
+            cause(err)
+        }
+        Hardlink(p: PathBuf, destination: PathBuf, err: io::Error) {
+            description("A hard link could not be created")
+            display("Failed to create hard link at '{}' to '{}'", p.display(), destination.display())
+            cause(err)
+        }
+        ReadDirectory(p: PathBuf, err: io::Error) {
+            description("The entries of a directory could not be read")
+            display("Failed to read directory '{}'", p.display())
+            cause(err)
+        }
+        Reflink(p: PathBuf, source: PathBuf, err: io::Error) {
+            description("A file could not be cloned")
+            display("Failed to clone '{}' to '{}'", source.display(), p.display())
+            cause(err)
+        }
+        ContentHash(p: PathBuf, err: io::Error) {
+            description("The content hash of a package could not be stored or removed")
+            display("Failed to update the stored content hash at '{}'", p.display())
+            cause(err)
+        }
+        Journal(p: PathBuf, err: io::Error) {
+            description("The journal of changes could not be read or written")
+            display("Failed to access journal at '{}'", p.display())
+            cause(err)
+        }
+        JournalInUse(p: PathBuf) {
+            description("The journal is used by another executor")
+            display("The journal at '{}' is in use by another process", p.display())
+        }
+        UnfinishedJournal(p: PathBuf) {
+            description("The journal contains instructions which were not applied completely")
+            display("The journal at '{}' contains unfinished instructions, which must be recovered first", p.display())
+        }
+    }
+}
+
+/// A `Visitor` which applies all instructions it receives to the file system.
+#[derive(Default)]
+pub struct FsExecutor {
+    linked: Vec<LinkedFile>,
+    /// If set, no file is cloned, as if no filesystem supported it.
+    without_reflinks: bool,
+}
+
+impl FsExecutor {
+    /// Returns an executor which treats every filesystem as unable to clone files, which makes
+    /// `CloneFiles` instructions fall back to hard links just like on such filesystems.
+    pub fn without_reflinks() -> FsExecutor {
+        FsExecutor {
+            without_reflinks: true,
+            ..Default::default()
+        }
+    }
+
+    /// Returns all files created by `HardlinkFiles` and `CloneFiles` instructions since the last
+    /// call, along with the strategy each of them was created with.
+    pub fn take_linked_files(&mut self) -> Vec<LinkedFile> {
+        ::std::mem::take(&mut self.linked)
+    }
+}
+
+/// Copy the directory `from` to `to` recursively, recreating symbolic links instead of following
+/// them.
+pub(crate) fn copy_recursively(from: &Path, to: &Path) -> Result<(), ExecutionError> {
+    let copy_error = |err| ExecutionError::Copy(from.to_owned(), to.to_owned(), err);
+    fs::create_dir_all(to).map_err(|err| ExecutionError::CreateDirectory(to.to_owned(), err))?;
+    for entry in fs::read_dir(from).map_err(copy_error)? {
+        let entry = entry.map_err(copy_error)?;
+        let file_type = entry.file_type().map_err(copy_error)?;
+        let destination = to.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_recursively(&entry.path(), &destination)?;
+        } else if file_type.is_symlink() {
+            let link = fs::read_link(entry.path()).map_err(copy_error)?;
+            symlink(&link, &destination).map_err(|err| ExecutionError::Symlink(destination, link, err))?;
+        } else {
+            fs::copy(entry.path(), &destination)
+                .map_err(|err| ExecutionError::Copy(entry.path(), destination, err))?;
+        }
+    }
+    Ok(())
+}
+
+/// The way a file was made to share its contents with another one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FileStrategy {
+    /// A copy-on-write clone, which shares disk blocks until either file is modified.
+    Reflink,
+    /// A hard link, which shares the file itself, including all future modifications.
+    Hardlink,
+    /// A plain copy, which shares nothing.
+    Copy,
+}
+
+/// A file created by a `HardlinkFiles` or `CloneFiles` instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinkedFile {
+    pub path: PathBuf,
+    /// The strategy the file ended up being created with, which may be a fallback of the one the
+    /// instruction asked for.
+    pub strategy: FileStrategy,
+}
+
+/// Create the file `to` as a copy-on-write clone of the file `from` using the `FICLONE` ioctl.
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let source = fs::File::open(from)?;
+    let destination = fs::File::create(to)?;
+    // SAFETY: both file descriptors are valid and owned by the files kept alive during the call
+    let cloned = if unsafe { libc::ioctl(destination.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == 0 {
+        source.metadata().and_then(|m| destination.set_permissions(m.permissions()))
+    } else {
+        Err(io::Error::last_os_error())
+    };
+    cloned.inspect_err(|_| {
+        fs::remove_file(to).ok();
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+/// Returns true if `err` indicates that the filesystem cannot clone a file, as opposed to a
+/// failure to access it.
+fn is_reflink_unsupported(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(code) => [libc::EOPNOTSUPP, libc::ENOTTY, libc::EINVAL, libc::EXDEV, libc::ENOSYS].contains(&code),
+        None => false,
+    }
+}
+
+/// Make the file `to` share the contents of the file `from` using `preferred`, and return the
+/// strategy which was used in the end. Clones fall back to hard links if the filesystem doesn't
+/// support them, or if `reflinks` is false, and hard links fall back to copies if both files are
+/// on different devices. The file is created next to `to` and renamed into place, replacing
+/// whatever was there.
+fn link_file(from: &Path, to: &Path, preferred: FileStrategy, reflinks: bool) -> Result<FileStrategy, ExecutionError> {
+    if same_file(from, to) {
+        return Ok(FileStrategy::Hardlink);
+    }
+    let link = sibling(to, "tmp");
+    if exists(&link) {
+        remove_symlink(&link)?;
+    }
+    let mut strategy = preferred;
+    if strategy == FileStrategy::Reflink {
+        let cloned = if reflinks {
+            reflink(from, &link)
+        } else {
+            Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+        };
+        match cloned {
+            Ok(()) => {}
+            Err(ref err) if is_reflink_unsupported(err) => strategy = FileStrategy::Hardlink,
+            Err(err) => return Err(ExecutionError::Reflink(link, from.to_owned(), err)),
+        }
+    }
+    if strategy == FileStrategy::Hardlink {
+        match fs::hard_link(from, &link) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::CrossesDevices => strategy = FileStrategy::Copy,
+            Err(err) => return Err(ExecutionError::Hardlink(link, from.to_owned(), err)),
+        }
+    }
+    if strategy == FileStrategy::Copy {
+        fs::copy(from, &link).map_err(|err| ExecutionError::Copy(from.to_owned(), link.clone(), err))?;
+    }
+    rename(&link, to)?;
+    Ok(strategy)
+}
+
+/// Make every file within `to` share the contents of the file at the same relative path within
+/// `from` using `preferred`, see `Instruction::HardlinkFiles`, and record each of them in `linked`.
+/// Symbolic links are recreated instead of being followed. Files are only cloned if `reflinks` is
+/// true.
+pub(crate) fn link_recursively(from: &Path,
+                               to: &Path,
+                               preferred: FileStrategy,
+                               reflinks: bool,
+                               linked: &mut Vec<LinkedFile>)
+                               -> Result<(), ExecutionError> {
+    let read_error = |err| ExecutionError::ReadDirectory(from.to_owned(), err);
+    fs::create_dir_all(to).map_err(|err| ExecutionError::CreateDirectory(to.to_owned(), err))?;
+    for entry in fs::read_dir(from).map_err(read_error)? {
+        let entry = entry.map_err(read_error)?;
+        let file_type = entry.file_type().map_err(read_error)?;
+        let destination = to.join(entry.file_name());
+        if file_type.is_dir() {
+            // Dependencies are packages of their own, which are linked independently
+            if entry.file_name() != "node_modules" {
+                link_recursively(&entry.path(), &destination, preferred, reflinks, linked)?;
+            }
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(entry.path()).map_err(read_error)?;
+            let link = sibling(&destination, "tmp");
+            if exists(&link) {
+                remove_symlink(&link)?;
+            }
+            create_symlink(&target, &link)?;
+            rename(&link, &destination)?;
+        } else {
+            let strategy = link_file(&entry.path(), &destination, preferred, reflinks)?;
+            linked.push(LinkedFile {
+                path: destination,
+                strategy,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the path of a hidden sibling of `p`, which is used to prepare or dispose of `p` without
+/// it ever being visible under an incomplete state. Hidden entries are ignored by npm and when
+/// searching for packages.
+pub(crate) fn sibling(p: &Path, suffix: &str) -> PathBuf {
+    let name = p.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    p.with_file_name(format!(".{}.npm-tools-{}", name, suffix))
+}
+
+fn exists(p: &Path) -> bool {
+    p.symlink_metadata().is_ok()
+}
+
+/// Move the directory `from` to `to`, which must not exist yet, creating all intermediate
+/// directories as needed. If both are located on different devices, `from` will be copied next to
+/// `to`, renamed into place, and removed afterwards.
+pub(crate) fn move_directory(from: &Path, to: &Path) -> Result<(), ExecutionError> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).map_err(|err| ExecutionError::CreateDirectory(parent.to_owned(), err))?;
+    }
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(ref err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            let copy = sibling(to, "tmp");
+            copy_recursively(from, &copy)?;
+            rename(&copy, to)?;
+            remove_directory(from)
+        }
+        Err(err) => Err(ExecutionError::Move(from.to_owned(), to.to_owned(), err)),
+    }
+}
+
+fn rename(from: &Path, to: &Path) -> Result<(), ExecutionError> {
+    fs::rename(from, to).map_err(|err| ExecutionError::Move(from.to_owned(), to.to_owned(), err))
+}
+
+pub(crate) fn remove_directory(p: &Path) -> Result<(), ExecutionError> {
+    fs::remove_dir_all(p).map_err(|err| ExecutionError::Remove(p.to_owned(), err))
+}
+
+pub(crate) fn remove_symlink(p: &Path) -> Result<(), ExecutionError> {
+    fs::remove_file(p).map_err(|err| ExecutionError::Remove(p.to_owned(), err))
+}
+
+pub(crate) fn create_symlink(destination: &Path, p: &Path) -> Result<(), ExecutionError> {
+    symlink(destination, p).map_err(|err| ExecutionError::Symlink(p.to_owned(), destination.to_owned(), err))
+}
+
+/// A primitive change to the file system, whose effect becomes visible atomically. Each
+/// instruction is applied as a sequence of steps, see `steps(...)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Step {
+    /// Create a symbolic link at `at`, pointing to `destination`, along with the directory
+    /// containing it.
+    Link { at: PathBuf, destination: PathBuf },
+    /// Copy the directory `from` to `to`.
+    Copy { from: PathBuf, to: PathBuf },
+    /// Move the directory `from` to `to`, which may be on another device.
+    Move { from: PathBuf, to: PathBuf },
+    /// Rename `from` to `to` on the same device.
+    Rename { from: PathBuf, to: PathBuf },
+    RemoveDirectory { p: PathBuf },
+    RemoveSymlink { p: PathBuf },
+    /// Record the content hash of the package directory `package` at `at`, replacing the one
+    /// recorded for a package previously stored at the same location.
+    StoreHash { package: PathBuf, at: PathBuf },
+    /// Remove the content hash stored at `at`, if there is one.
+    RemoveHash { at: PathBuf },
+    /// Make all files within `to` share the contents of the ones within `from` using `strategy`,
+    /// or its fallbacks, one file at a time.
+    LinkFiles {
+        from: PathBuf,
+        to: PathBuf,
+        strategy: FileStrategy,
+    },
+}
+
+/// Returns the steps to apply `instruction` with, along with the index of the step which makes the
+/// instruction take effect. Steps before it only prepare hidden siblings, and can be rolled back,
+/// whereas the remaining steps must be finished once it was started.
+///
+/// Symbolic links are created under a hidden name and renamed into place, which keeps the time
+/// during which a package path is missing as short as possible. The content hash stored for a
+/// package within the repository is replaced whenever a package is moved to its location, and
+/// removed along with the package.
+pub(crate) fn steps(instruction: &Instruction) -> (Vec<Step>, usize) {
+    match *instruction {
+        Instruction::MoveAndSymlink { from_here, to_here, symlink_destination } => {
+            let link = sibling(from_here, "tmp");
+            let mut steps = vec![Step::Link {
+                                     at: link.clone(),
+                                     destination: symlink_destination.to_owned(),
+                                 },
+                                 Step::Move {
+                                     from: from_here.to_owned(),
+                                     to: to_here.to_owned(),
+                                 },
+                                 Step::Rename {
+                                     from: link,
+                                     to: from_here.to_owned(),
+                                 }];
+            if let Some(at) = stored_hash_path_of(to_here) {
+                steps.push(Step::StoreHash {
+                    package: to_here.to_owned(),
+                    at,
+                });
+            }
+            (steps, 1)
+        }
+        Instruction::ReplaceWithSymlink { this_directory, symlink_destination } => {
+            let (link, old) = (sibling(this_directory, "tmp"), sibling(this_directory, "old"));
+            (vec![Step::Link {
+                      at: link.clone(),
+                      destination: symlink_destination.to_owned(),
+                  },
+                  Step::Rename {
+                      from: this_directory.to_owned(),
+                      to: old.clone(),
+                  },
+                  Step::Rename {
+                      from: link,
+                      to: this_directory.to_owned(),
+                  },
+                  Step::RemoveDirectory { p: old }],
+             1)
+        }
+        Instruction::CopyBackFromRepo { this_symlink, from_here } => {
+            let copy = sibling(this_symlink, "tmp");
+            (vec![Step::Copy {
+                      from: from_here.to_owned(),
+                      to: copy.clone(),
+                  },
+                  Step::RemoveSymlink { p: this_symlink.to_owned() },
+                  Step::Rename {
+                      from: copy,
+                      to: this_symlink.to_owned(),
+                  }],
+             1)
+        }
+        Instruction::RemoveFromRepo { this_directory } => {
+            let old = sibling(this_directory, "old");
+            let mut steps = vec![Step::Rename {
+                                     from: this_directory.to_owned(),
+                                     to: old.clone(),
+                                 }];
+            if let Some(at) = stored_hash_path_of(this_directory) {
+                steps.push(Step::RemoveHash { at });
+            }
+            steps.push(Step::RemoveDirectory { p: old });
+            (steps, 0)
+        }
+        Instruction::MoveWithinRepo { from_here, to_here } => {
+            (vec![Step::Move {
+                      from: from_here.to_owned(),
+                      to: to_here.to_owned(),
+                  }],
+             0)
+        }
+        Instruction::RemoveSymlink { this_symlink } => (vec![Step::RemoveSymlink { p: this_symlink.to_owned() }], 0),
+        Instruction::RetargetSymlink { this_symlink, symlink_destination } |
+        Instruction::CreateSymlink { this_symlink, symlink_destination } => {
+            let link = sibling(this_symlink, "tmp");
+            (vec![Step::Link {
+                      at: link.clone(),
+                      destination: symlink_destination.to_owned(),
+                  },
+                  Step::Rename {
+                      from: link,
+                      to: this_symlink.to_owned(),
+                  }],
+             1)
+        }
+        Instruction::HardlinkFiles { from_here, to_here } => {
+            (vec![Step::LinkFiles {
+                      from: from_here.to_owned(),
+                      to: to_here.to_owned(),
+                      strategy: FileStrategy::Hardlink,
+                  }],
+             0)
+        }
+        Instruction::CloneFiles { from_here, to_here } => {
+            (vec![Step::LinkFiles {
+                      from: from_here.to_owned(),
+                      to: to_here.to_owned(),
+                      strategy: FileStrategy::Reflink,
+                  }],
+             0)
+        }
+    }
+}
+
+impl Step {
+    /// Apply this step. If `recovering` is true, the step may have been applied partially or
+    /// completely before, and is finished from whichever state it is in.
+    /// All files created by `Step::LinkFiles` are recorded in `linked`, and are only cloned if
+    /// `reflinks` is true.
+    pub(crate) fn apply(&self,
+                        recovering: bool,
+                        reflinks: bool,
+                        linked: &mut Vec<LinkedFile>)
+                        -> Result<(), ExecutionError> {
+        match *self {
+            Step::Link { ref at, ref destination } => {
+                if recovering && exists(at) {
+                    remove_symlink(at)?;
+                }
+                if let Some(parent) = at.parent() {
+                    fs::create_dir_all(parent)
+                        .map_err(|err| ExecutionError::CreateDirectory(parent.to_owned(), err))?;
+                }
+                create_symlink(destination, at)
+            }
+            Step::Copy { ref from, ref to } => {
+                if recovering && exists(to) {
+                    remove_directory(to)?;
+                }
+                copy_recursively(from, to)
+            }
+            Step::Move { ref from, ref to } => {
+                if !recovering {
+                    return move_directory(from, to);
+                }
+                // `to` only appears once it is complete, and `from` is only removed afterwards
+                if exists(to) {
+                    return if exists(from) { remove_directory(from) } else { Ok(()) };
+                }
+                let copy = sibling(to, "tmp");
+                if exists(&copy) {
+                    remove_directory(&copy)?;
+                }
+                move_directory(from, to)
+            }
+            Step::Rename { ref from, ref to } => {
+                if recovering && !exists(from) && exists(to) {
+                    return Ok(());
+                }
+                rename(from, to)
+            }
+            Step::RemoveDirectory { ref p } => {
+                if recovering && !exists(p) {
+                    return Ok(());
+                }
+                remove_directory(p)
+            }
+            Step::RemoveSymlink { ref p } => {
+                if recovering && !exists(p) {
+                    return Ok(());
+                }
+                remove_symlink(p)
+            }
+            Step::StoreHash { ref package, ref at } => {
+                content_hash(package)
+                    .and_then(|hash| write_hash(at, &hash))
+                    .map_err(|err| ExecutionError::ContentHash(at.to_owned(), err))
+            }
+            Step::RemoveHash { ref at } => {
+                match fs::remove_file(at) {
+                    Ok(()) => Ok(()),
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                    Err(err) => Err(ExecutionError::ContentHash(at.to_owned(), err)),
+                }
+            }
+            // Every file is replaced atomically, which makes this safe to repeat
+            Step::LinkFiles { ref from, ref to, strategy } => link_recursively(from, to, strategy, reflinks, linked),
+        }
+    }
+
+    /// Undo this step, which may have been applied partially or not at all.
+    /// Removals cannot be undone.
+    pub(crate) fn roll_back(&self) -> Result<(), ExecutionError> {
+        match *self {
+            Step::Link { ref at, .. } if exists(at) => remove_symlink(at),
+            Step::Copy { ref to, .. } if exists(to) => remove_directory(to),
+            Step::Move { ref from, ref to } if !exists(from) && exists(to) => move_directory(to, from),
+            Step::Rename { ref from, ref to } if !exists(from) && exists(to) => rename(to, from),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Undo `steps` in reverse order.
+pub(crate) fn roll_back(steps: &[Step]) -> Result<(), ExecutionError> {
+    steps.iter().rev().try_for_each(Step::roll_back)
+}
+
+impl Visitor for FsExecutor {
+    type Error = ExecutionError;
+
+    fn error(&mut self, _package: &PackageInfo, _err: &Error) {}
+
+    fn change(&mut self, action: Instruction) -> Result<(), Self::Error> {
+        let (steps, commit) = steps(&action);
+        for (index, step) in steps.iter().enumerate() {
+            if let Err(err) = step.apply(false, !self.without_reflinks, &mut self.linked) {
+                if index <= commit {
+                    // The original error is more relevant than a failure to clean up
+                    roll_back(&steps[..index + 1]).ok();
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+use std::error::Error as StdError;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use semver::Version;
+
+use dedup::{Error, Instruction, PackageInfo, Visitor, fetch_string, read_package_json};
+use exec::sibling;
+use paths::{absolute, lock_exclusively};
+use walk::{find_packages, find_repo_links, find_repo_packages};
+
+/// Returns the path to the file listing all projects which use the repository at `repo`.
+fn registry_path(repo: &Path) -> PathBuf {
+    repo.join(".npm-tools").join("projects")
+}
+
+/// Returns the absolute paths of all projects registered as users of `repo`, in the order they
+/// were registered.
+pub fn registered_projects<P>(repo: P) -> Result<Vec<PathBuf>, Error>
+    where P: AsRef<Path>
+{
+    let path = registry_path(repo.as_ref());
+    let mut content = String::new();
+    match fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut content)) {
+        Ok(_) => Ok(content.lines().filter(|l| !l.is_empty()).map(PathBuf::from).collect()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(Error::ProjectRegistry(path, err)),
+    }
+}
+
+/// Let `change` modify the projects registered as users of `repo`, and store them if it returns
+/// true. The registry is locked meanwhile, which keeps concurrent changes from overwriting each
+/// other, and it is replaced atomically, which keeps it intact if the process dies.
+fn update_projects<F>(repo: &Path, change: F) -> Result<(), Error>
+    where F: FnOnce(&mut Vec<PathBuf>) -> bool
+{
+    let path = registry_path(repo);
+    let registry_error = |err| Error::ProjectRegistry(path.clone(), err);
+    fs::create_dir_all(path.parent().expect("registry to be in a directory")).map_err(registry_error)?;
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(sibling(&path, "lock"))
+        .map_err(registry_error)?;
+    lock_exclusively(&lock, true).map_err(registry_error)?;
+
+    let mut projects = registered_projects(repo)?;
+    if !change(&mut projects) {
+        return Ok(());
+    }
+    let tmp = sibling(&path, "tmp");
+    fs::File::create(&tmp)
+        .and_then(|mut f| {
+            for project in &projects {
+                writeln!(f, "{}", project.display())?;
+            }
+            f.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, &path))
+        .map_err(registry_error)
+}
+
+/// Register the project at `project` as user of `repo`, which protects all packages it links to
+/// from being collected by `gc(...)`. Registering a project more than once has no effect.
+pub fn register_project<R, P>(repo: R, project: P) -> Result<(), Error>
+    where R: AsRef<Path>,
+          P: AsRef<Path>
+{
+    let project = absolute(project.as_ref());
+    update_projects(repo.as_ref(), |projects| {
+        if projects.contains(&project) {
+            return false;
+        }
+        projects.push(project);
+        true
+    })
+}
+
+/// Remove the project at `project` from the list of users of `repo`.
+pub fn unregister_project<R, P>(repo: R, project: P) -> Result<(), Error>
+    where R: AsRef<Path>,
+          P: AsRef<Path>
+{
+    let project = absolute(project.as_ref());
+    update_projects(repo.as_ref(), |projects| {
+        let count = projects.len();
+        projects.retain(|p| *p != project);
+        projects.len() != count
+    })
+}
+
+/// A package version within the repository which is not used by any registered project.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnusedPackage {
+    pub name: String,
+    pub version: String,
+    /// The `<name>/<version>` directory within the repository.
+    pub directory: PathBuf,
+    /// The amount of bytes used by all files within `directory`.
+    pub size_in_bytes: u64,
+}
+
+/// The result of a garbage collection run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub unused: Vec<UnusedPackage>,
+}
+
+impl GcReport {
+    /// The amount of bytes which are or would be freed by removing all unused packages.
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.unused.iter().map(|p| p.size_in_bytes).sum()
+    }
+}
+
+fn size_of(p: &Path) -> io::Result<u64> {
+    let metadata = p.symlink_metadata()?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(p)? {
+        total += size_of(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// Returns the name and version of every package directory within the `node_modules` hierarchy
+/// of `project`, which may share its files with the package in the repository via hard links or
+/// clones. Packages without a readable name and version are skipped.
+fn package_copies(project: &Path, errors: &mut Vec<Error>) -> Vec<(String, String)> {
+    let mut copies = Vec::new();
+    if !project.join("node_modules").is_dir() {
+        return copies;
+    }
+    for package in find_packages(project) {
+        let package = match package {
+            Ok(package) => package,
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+        let name_and_version = read_package_json(&package).ok().and_then(|pj| {
+            let version = fetch_string(&pj, &package, "version").ok().and_then(|v| Version::parse(&v).ok())?;
+            fetch_string(&pj, &package, "name").ok().map(|name| (name, format!("{}", version)))
+        });
+        copies.extend(name_and_version);
+    }
+    copies
+}
+
+/// Determine which packages in `repo` are not reachable from any registered project, without
+/// changing anything. This is the dry-run version of `gc(...)`.
+///
+/// A package version is reachable if a symbolic link within a project's `node_modules` hierarchy
+/// points into it, directly or through other packages in the repository. As projects deduplicated
+/// with `LinkMode::Hardlink` or `LinkMode::Reflink` keep real directories, a package version is
+/// reachable as well if a project contains a package directory of the same name and version.
+///
+/// Fails with `Error::NoRegisteredProjects` if no project is registered, as every package would
+/// be unused otherwise.
+pub fn gc_report<R>(repo: R) -> Result<GcReport, Vec<Error>>
+    where R: AsRef<Path>
+{
+    let repo = absolute(repo.as_ref());
+    let mut errors = Vec::new();
+    let projects = registered_projects(&repo).map_err(|err| vec![err])?;
+    if projects.is_empty() {
+        return Err(vec![Error::NoRegisteredProjects(repo)]);
+    }
+    let targets: Vec<_> = projects.iter()
+        .flat_map(|p| find_repo_links(&p.join("node_modules"), &repo, &mut errors))
+        .map(|l| l.target)
+        .collect();
+    let copies: Vec<_> = projects.iter().flat_map(|p| package_copies(p, &mut errors)).collect();
+
+    let mut report = GcReport::default();
+    for package in find_repo_packages(&repo, &mut errors) {
+        if targets.iter().any(|t| t.starts_with(&package.directory)) ||
+           copies.iter().any(|(name, version)| *name == package.name && *version == package.version) {
+            continue;
+        }
+        match size_of(&package.directory) {
+            Ok(size_in_bytes) => {
+                report.unused.push(UnusedPackage {
+                    name: package.name,
+                    version: package.version,
+                    directory: package.directory,
+                    size_in_bytes,
+                })
+            }
+            Err(err) => errors.push(Error::ReadDirectory(package.directory, err)),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(report)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Instruct `visitor` to remove all packages from `repo` which are not used by any of its
+/// registered projects, see `gc_report(...)` for details.
+/// Returns the report of the unused packages that were handed to the visitor.
+pub fn gc<R, V, E>(repo: R, visitor: &mut V) -> Result<GcReport, Vec<Error>>
+    where R: AsRef<Path>,
+          E: StdError + 'static,
+          V: Visitor<Error = E>
+{
+    let report = gc_report(repo.as_ref())?;
+    let mut errors = Vec::new();
+    for package in &report.unused {
+        if let Err(err) = visitor.change(Instruction::RemoveFromRepo { this_directory: &package.directory }) {
+            let err = Error::Visitor(package.directory.clone(), Box::new(err));
+            visitor.error(&PackageInfo {
+                              directory: package.directory.clone(),
+                              root_directory: repo.as_ref().to_owned(),
+                          },
+                          &err);
+            errors.push(err);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(report)
+    } else {
+        Err(errors)
+    }
+}
@@ -0,0 +1,132 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Value};
+use sha1_smol::Sha1;
+
+fn hash_file(p: &Path) -> io::Result<String> {
+    let mut f = fs::File::open(p)?;
+    let mut hasher = Sha1::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        match f.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hasher.digest().to_string())
+}
+
+/// Hash the package.json at `p` without the fields npm adds when installing a package, like
+/// `_where`, `_from` or `_resolved`, which differ between otherwise identical copies.
+/// Files which are no JSON object are hashed as they are.
+fn hash_manifest(p: &Path) -> io::Result<String> {
+    let mut content = Vec::new();
+    fs::File::open(p)?.read_to_end(&mut content)?;
+    let normalized = match serde_json::from_slice(&content) {
+        Ok(Value::Object(mut m)) => {
+            let metadata: Vec<_> = m.keys().filter(|k| k.starts_with('_')).cloned().collect();
+            for key in metadata {
+                m.remove(&key);
+            }
+            serde_json::to_vec(&Value::Object(m)).unwrap_or(content)
+        }
+        _ => content,
+    };
+    let mut hasher = Sha1::new();
+    hasher.update(&normalized);
+    Ok(hasher.digest().to_string())
+}
+
+fn hash_tree(dir: &Path, is_package_root: bool) -> io::Result<String> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        entries.push(entry?);
+    }
+    entries.sort_by_key(|e| e.file_name());
+
+    let mut hasher = Sha1::new();
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        let (kind, hash) = if file_type.is_symlink() {
+            let mut link = Sha1::new();
+            link.update(fs::read_link(entry.path())?.to_string_lossy().as_bytes());
+            ("link", link.digest().to_string())
+        } else if file_type.is_dir() {
+            // Dependencies are managed independently, and may be replaced by links at any time
+            if entry.file_name() == "node_modules" {
+                continue;
+            }
+            ("tree", hash_tree(&entry.path(), false)?)
+        } else if is_package_root && entry.file_name() == "package.json" {
+            ("file", hash_manifest(&entry.path())?)
+        } else {
+            ("file", hash_file(&entry.path())?)
+        };
+        hasher.update(kind.as_bytes());
+        hasher.update(b" ");
+        hasher.update(entry.file_name().to_string_lossy().as_bytes());
+        hasher.update(b"\0");
+        hasher.update(hash.as_bytes());
+    }
+    Ok(hasher.digest().to_string())
+}
+
+/// Compute a hash over the contents of the package in `dir`, which changes whenever a file, a
+/// symbolic link or the directory structure changes. Nested `node_modules` directories are not
+/// part of the hash, and neither are the fields npm adds to the package.json when installing the
+/// package, which makes the hash of all copies of a package version the same unless one of them
+/// was modified.
+pub fn content_hash<P>(dir: P) -> io::Result<String>
+    where P: AsRef<Path>
+{
+    hash_tree(dir.as_ref(), true)
+}
+
+/// Returns the path at which the content hash of the package in `dir` is stored in `repo`. Hashes
+/// are kept at the same path relative to `.npm-tools/hashes` as their package is relative to the
+/// repository, so the location follows the `RepoLayout` the package was placed with.
+pub(crate) fn stored_hash_path(repo: &Path, dir: &Path) -> Option<PathBuf> {
+    dir.strip_prefix(repo).ok().map(|rel| repo.join(".npm-tools").join("hashes").join(rel))
+}
+
+/// Read the content hash stored for the package in `dir` within `repo`, if there is one.
+pub(crate) fn read_stored_hash(repo: &Path, dir: &Path) -> io::Result<Option<String>> {
+    let path = match stored_hash_path(repo, dir) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let mut content = String::new();
+    match fs::File::open(path).and_then(|mut f| f.read_to_string(&mut content)) {
+        Ok(_) => Ok(Some(content.trim().to_owned())),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Returns the path at which the content hash of the package directory `dir` is stored, if `dir`
+/// is located within a repository. The repository is the closest ancestor of `dir` containing a
+/// `.npm-tools` directory.
+pub(crate) fn stored_hash_path_of(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .skip(1)
+        .find(|repo| repo.join(".npm-tools").is_dir())
+        .and_then(|repo| stored_hash_path(repo, dir))
+}
+
+/// Store `hash` as the content hash of the package in `dir` within `repo`.
+pub(crate) fn write_stored_hash(repo: &Path, dir: &Path, hash: &str) -> io::Result<()> {
+    match stored_hash_path(repo, dir) {
+        Some(path) => write_hash(&path, hash),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "package is not located within the repository")),
+    }
+}
+
+/// Store `hash` at `path`, replacing the hash stored there before.
+pub(crate) fn write_hash(path: &Path, hash: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::File::create(path)?.write_all(hash.as_bytes())
+}
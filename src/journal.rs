@@ -0,0 +1,310 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Map, Value};
+
+use dedup::{Error, Instruction, InstructionOwned, PackageInfo, Visitor};
+use exec::{ExecutionError, LinkedFile, roll_back, steps};
+use paths::lock_exclusively;
+
+/// Returns the path to the journal of changes applied to `repo` and the projects using it.
+fn journal_path(repo: &Path) -> PathBuf {
+    repo.join(".npm-tools").join("journal")
+}
+
+/// A line within the journal.
+enum Entry {
+    Begin { id: u64, instruction: InstructionOwned },
+    Start { id: u64, step: usize },
+    Done { id: u64, step: usize },
+    End { id: u64 },
+}
+
+impl Entry {
+    fn to_json(&self) -> Value {
+        let mut m = Map::new();
+        let (id, event) = match *self {
+            Entry::Begin { id, ref instruction } => {
+                m.insert(String::from("instruction"), instruction.as_instruction().to_json());
+                (id, "begin")
+            }
+            Entry::Start { id, step } => {
+                m.insert(String::from("step"), Value::U64(step as u64));
+                (id, "start")
+            }
+            Entry::Done { id, step } => {
+                m.insert(String::from("step"), Value::U64(step as u64));
+                (id, "done")
+            }
+            Entry::End { id } => (id, "end"),
+        };
+        m.insert(String::from("id"), Value::U64(id));
+        m.insert(String::from("event"), Value::String(event.to_owned()));
+        Value::Object(m)
+    }
+
+    fn from_json(v: &Value) -> Option<Entry> {
+        let id = v.find("id").and_then(Value::as_u64)?;
+        let step = || v.find("step").and_then(Value::as_u64).map(|s| s as usize);
+        Some(match v.find("event").and_then(Value::as_str)? {
+            "begin" => {
+                Entry::Begin {
+                    id,
+                    instruction: InstructionOwned::from_json(v.find("instruction")?).ok()?,
+                }
+            }
+            "start" => Entry::Start { id, step: step()? },
+            "done" => Entry::Done { id, step: step()? },
+            "end" => Entry::End { id },
+            _ => return None,
+        })
+    }
+}
+
+/// An instruction which was begun, but not ended.
+struct Unfinished {
+    id: u64,
+    instruction: InstructionOwned,
+    /// The highest step which was started.
+    started: Option<usize>,
+    /// The highest step which was completed.
+    done: Option<usize>,
+}
+
+/// Read all entries of the journal at `path`, which might not exist.
+/// The last line is ignored if it is incomplete, as the process may have died while writing it.
+fn read_journal(path: &Path) -> Result<Vec<Entry>, ExecutionError> {
+    let mut content = String::new();
+    match fs::File::open(path).and_then(|mut f| f.read_to_string(&mut content)) {
+        Ok(_) => {}
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(ExecutionError::Journal(path.to_owned(), err)),
+    }
+    let complete = content.rfind('\n').map(|i| &content[..i]).unwrap_or("");
+    complete.lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            serde_json::from_str(l).ok().and_then(|v| Entry::from_json(&v)).ok_or_else(|| {
+                ExecutionError::Journal(path.to_owned(),
+                                        io::Error::new(io::ErrorKind::InvalidData,
+                                                       format!("Unexpected journal entry: {}", l)))
+            })
+        })
+        .collect()
+}
+
+fn unfinished(entries: Vec<Entry>) -> Vec<Unfinished> {
+    let mut pending: Vec<Unfinished> = Vec::new();
+    for entry in entries {
+        match entry {
+            Entry::Begin { id, instruction } => {
+                pending.push(Unfinished {
+                    id,
+                    instruction,
+                    started: None,
+                    done: None,
+                })
+            }
+            Entry::Start { id, step } => {
+                if let Some(u) = pending.iter_mut().find(|u| u.id == id) {
+                    u.started = Some(step);
+                }
+            }
+            Entry::Done { id, step } => {
+                if let Some(u) = pending.iter_mut().find(|u| u.id == id) {
+                    u.done = Some(step);
+                }
+            }
+            Entry::End { id } => pending.retain(|u| u.id != id),
+        }
+    }
+    pending
+}
+
+/// The journal file, which is exclusively locked for as long as it is open.
+struct Journal {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl Journal {
+    /// Open the journal at `path` for appending, and lock it to make sure no other executor
+    /// writes to it or recovers from it at the same time. Fails with `ExecutionError::JournalInUse`
+    /// if it is locked already.
+    fn open(path: PathBuf) -> Result<Journal, ExecutionError> {
+        let journal_error = |err| ExecutionError::Journal(path.clone(), err);
+        fs::create_dir_all(path.parent().expect("journal to be in a directory")).map_err(journal_error)?;
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(journal_error)?;
+        match lock_exclusively(&file, false) {
+            Ok(()) => Ok(Journal { path, file }),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Err(ExecutionError::JournalInUse(path)),
+            Err(err) => Err(journal_error(err)),
+        }
+    }
+
+    /// Remove all entries, which makes sure the journal doesn't grow forever.
+    fn clear(&mut self) -> Result<(), ExecutionError> {
+        self.file.set_len(0).map_err(|err| ExecutionError::Journal(self.path.clone(), err))
+    }
+
+    /// Append `entry`, and make sure it is stored persistently before returning.
+    fn write(&mut self, entry: Entry) -> Result<(), ExecutionError> {
+        writeln!(self.file, "{}", entry.to_json())
+            .and_then(|_| self.file.sync_data())
+            .map_err(|err| ExecutionError::Journal(self.path.clone(), err))
+    }
+}
+
+/// A `Visitor` which applies all instructions it receives to the file system just like
+/// `FsExecutor`, but records each step in a journal within the repository before and after
+/// applying it. If the process dies in the middle of an instruction, `recover(...)` uses the
+/// journal to roll it back or to finish it.
+///
+/// If a step fails, the instruction is rolled back right away if it did not take effect yet.
+/// Otherwise it remains unfinished until `recover(...)` is called.
+pub struct JournaledExecutor {
+    journal: Journal,
+    next_id: u64,
+    linked: Vec<LinkedFile>,
+    /// If set, no file is cloned, as if no filesystem supported it.
+    without_reflinks: bool,
+}
+
+impl JournaledExecutor {
+    /// Returns an executor with an empty journal in `repo`, which it keeps locked until it is
+    /// dropped. Fails with `ExecutionError::JournalInUse` if another executor or `recover(...)`
+    /// uses the journal, and with `ExecutionError::UnfinishedJournal` if the existing journal
+    /// has unfinished instructions.
+    pub fn new<R>(repo: R) -> Result<JournaledExecutor, ExecutionError>
+        where R: AsRef<Path>
+    {
+        JournaledExecutor::open(repo.as_ref(), false)
+    }
+
+    /// As `new(...)`, but returns an executor which treats every filesystem as unable to clone
+    /// files, see `FsExecutor::without_reflinks()`.
+    pub fn without_reflinks<R>(repo: R) -> Result<JournaledExecutor, ExecutionError>
+        where R: AsRef<Path>
+    {
+        JournaledExecutor::open(repo.as_ref(), true)
+    }
+
+    fn open(repo: &Path, without_reflinks: bool) -> Result<JournaledExecutor, ExecutionError> {
+        let path = journal_path(repo);
+        let mut journal = Journal::open(path.clone())?;
+        if !unfinished(read_journal(&path)?).is_empty() {
+            return Err(ExecutionError::UnfinishedJournal(path));
+        }
+        journal.clear()?;
+        Ok(JournaledExecutor {
+            journal,
+            next_id: 0,
+            linked: Vec::new(),
+            without_reflinks,
+        })
+    }
+
+    /// Returns all files created by `HardlinkFiles` and `CloneFiles` instructions since the last
+    /// call, along with the strategy each of them was created with.
+    pub fn take_linked_files(&mut self) -> Vec<LinkedFile> {
+        ::std::mem::take(&mut self.linked)
+    }
+}
+
+impl Visitor for JournaledExecutor {
+    type Error = ExecutionError;
+
+    fn error(&mut self, _package: &PackageInfo, _err: &Error) {}
+
+    fn change(&mut self, action: Instruction) -> Result<(), Self::Error> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (steps, commit) = steps(&action);
+        self.journal.write(Entry::Begin {
+            id,
+            instruction: action.into(),
+        })?;
+        for (index, step) in steps.iter().enumerate() {
+            self.journal.write(Entry::Start { id, step: index })?;
+            if let Err(err) = step.apply(false, !self.without_reflinks, &mut self.linked) {
+                if index <= commit && roll_back(&steps[..index + 1]).is_ok() {
+                    self.journal.write(Entry::End { id })?;
+                }
+                return Err(err);
+            }
+            self.journal.write(Entry::Done { id, step: index })?;
+        }
+        self.journal.write(Entry::End { id })
+    }
+}
+
+/// The way an unfinished instruction was dealt with by `recover(...)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// The instruction did not take effect yet, and all of its preparations were undone.
+    RolledBack,
+    /// The instruction took effect partially, and was applied completely.
+    Finished,
+}
+
+/// An instruction which was left unfinished, and how it was recovered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recovered {
+    pub instruction: InstructionOwned,
+    pub recovery: Recovery,
+}
+
+/// Use the journal in `repo` to deal with all instructions which a `JournaledExecutor` did not
+/// apply completely, for instance because the process died. Instructions which did not take
+/// effect yet are rolled back, all others are finished by retrying the step which was interrupted
+/// and applying all steps after it.
+/// The journal is removed once all instructions were recovered. Fails with
+/// `ExecutionError::JournalInUse` if a `JournaledExecutor` is still applying instructions.
+///
+/// Returns the recovered instructions in the order they were begun, which is empty if there was
+/// nothing to do.
+pub fn recover<R>(repo: R) -> Result<Vec<Recovered>, ExecutionError>
+    where R: AsRef<Path>
+{
+    let path = journal_path(repo.as_ref());
+    if unfinished(read_journal(&path)?).is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut journal = Journal::open(path.clone())?;
+    // The journal may have been recovered by someone else before it was locked
+    let pending = unfinished(read_journal(&path)?);
+    let mut recovered = Vec::new();
+    let mut linked = Vec::new();
+    for u in pending {
+        let (steps, commit) = steps(&u.instruction.as_instruction());
+        let recovery = match u.started {
+            Some(started) if started >= commit => {
+                // Completed steps are not applied again, only the one which may have been interrupted
+                let next = if u.done == Some(started) { started + 1 } else { started };
+                for (index, step) in steps.iter().enumerate().skip(next) {
+                    journal.write(Entry::Start { id: u.id, step: index })?;
+                    step.apply(true, true, &mut linked)?;
+                    journal.write(Entry::Done { id: u.id, step: index })?;
+                }
+                Recovery::Finished
+            }
+            started => {
+                roll_back(&steps[..started.map(|s| s + 1).unwrap_or(0)])?;
+                Recovery::RolledBack
+            }
+        };
+        journal.write(Entry::End { id: u.id })?;
+        recovered.push(Recovered {
+            instruction: u.instruction,
+            recovery,
+        });
+    }
+    fs::remove_file(&path).map_err(|err| ExecutionError::Journal(path, err))?;
+    Ok(recovered)
+}
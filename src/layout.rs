@@ -0,0 +1,91 @@
+use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use semver::Version;
+
+use dedup::repo_destination;
+
+/// Determines where package versions are stored within the repository.
+pub trait RepoLayout: Debug + Send + Sync {
+    /// Returns the directory package `name` at `version` is stored in within `repo`.
+    fn package_directory(&self, repo: &Path, name: &str, version: &Version) -> PathBuf;
+
+    /// Returns all versions of package `name` stored within `repo`, in no particular order.
+    fn versions(&self, repo: &Path, name: &str) -> Vec<Version>;
+
+    /// Returns the version of package `name` whose directory within `repo` contains `path`,
+    /// along with the remainder of `path` below that directory.
+    fn locate(&self, repo: &Path, name: &str, path: &Path) -> Option<(Version, PathBuf)> {
+        self.versions(repo, name).into_iter().find_map(|version| {
+            let rest = path.strip_prefix(self.package_directory(repo, name, &version)).ok()?.to_owned();
+            Some((version, rest))
+        })
+    }
+
+    /// Returns the `node_modules` directory containing the directory of package `name` at
+    /// `version`, if the dependencies of the package are linked next to it. Otherwise, which is the
+    /// default, packages can only find the dependencies within their own `node_modules` directory.
+    fn dependency_directory(&self, _repo: &Path, _name: &str, _version: &Version) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Parse all names of the directories within `dir` which start with `prefix` as version.
+fn versions_in(dir: &Path, prefix: &str) -> Vec<Version> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries.filter_map(Result::ok)
+        .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .filter_map(|e| {
+            e.file_name().to_str().and_then(|n| n.strip_prefix(prefix)).and_then(|v| Version::parse(v).ok())
+        })
+        .collect()
+}
+
+/// Stores each package version at `<repo>/<name>/<version>`, or `<repo>/@scope/<name>/<version>`
+/// for scoped packages. This is the default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NameVersionLayout;
+
+impl RepoLayout for NameVersionLayout {
+    fn package_directory(&self, repo: &Path, name: &str, version: &Version) -> PathBuf {
+        repo_destination(repo, name, version)
+    }
+
+    fn versions(&self, repo: &Path, name: &str) -> Vec<Version> {
+        versions_in(&name.split('/').fold(repo.to_path_buf(), |p, c| p.join(c)), "")
+    }
+}
+
+/// Stores each package version like pnpm does, at `<repo>/.pnpm/<name>@<version>/node_modules/<name>`,
+/// where the `/` of scoped package names is replaced by `+` in the first occurrence of the name.
+///
+/// The dependencies of each package are linked into the `node_modules` directory next to it,
+/// which allows Node to resolve them from the real path of the package without relying on the
+/// `node_modules` directory of the project.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PnpmLayout;
+
+impl PnpmLayout {
+    fn version_directory(repo: &Path, name: &str, version: &Version) -> PathBuf {
+        repo.join(".pnpm").join(format!("{}@{}", name.replace('/', "+"), version))
+    }
+}
+
+impl RepoLayout for PnpmLayout {
+    fn package_directory(&self, repo: &Path, name: &str, version: &Version) -> PathBuf {
+        name.split('/').fold(PnpmLayout::version_directory(repo, name, version).join("node_modules"),
+                             |p, c| p.join(c))
+    }
+
+    fn versions(&self, repo: &Path, name: &str) -> Vec<Version> {
+        versions_in(&repo.join(".pnpm"), &format!("{}@", name.replace('/', "+")))
+    }
+
+    fn dependency_directory(&self, repo: &Path, name: &str, version: &Version) -> Option<PathBuf> {
+        Some(PnpmLayout::version_directory(repo, name, version).join("node_modules"))
+    }
+}
@@ -17,21 +17,53 @@
 //!   a project's `node_modules` dir, or on all the ones in the npm-repo.
 //! * collect and remove packages in the npm-repo which are not used anymore.
 //!   - This should be based on a list of repos which use it ... this can at
-//!   least help to efficiently optimize the repo.
+//!     least help to efficiently optimize the repo.
 //! * Revert all changes to the `node_modules` directory to allow npm to operate
 //!   naturally on it.
 //! - **IMPORTANT**: figure out whether it will mess with an existing setup -
-//! it could
-//!   very well be that
-//!   it updates packages in place, which are actually living in our
-//!   npm-repo, and thus
-//!   messes with the versions. If that's possible, one would need a
-//!   **sanity check/fix** for the repo as well.
+//!   it could very well be that it updates packages in place, which are
+//!   actually living in our npm-repo, and thus messes with the versions.
+//!   If that's possible, one would need a **sanity check/fix** for the repo
+//!   as well.
+extern crate serde;
 extern crate serde_json;
 #[macro_use]
 extern crate quick_error;
 extern crate semver;
+extern crate sha1_smol;
+extern crate libc;
 
 mod dedup;
+mod walk;
+mod exec;
+mod paths;
+mod platform;
+mod revert;
+mod gc;
+mod hash;
+mod verify;
+mod resolve;
+mod plan;
+mod journal;
+mod parallel;
+mod spec;
+mod range;
+mod lock;
+mod yarn;
+mod layout;
 
 pub use dedup::*;
+pub use walk::*;
+pub use exec::*;
+pub use platform::{PlatformSegment, current_platform};
+pub use revert::*;
+pub use gc::*;
+pub use hash::content_hash;
+pub use verify::*;
+pub use plan::*;
+pub use journal::*;
+pub use spec::DependencySpec;
+pub use range::{RangeParseError, VersionRange};
+pub use lock::*;
+pub use yarn::*;
+pub use layout::{NameVersionLayout, PnpmLayout, RepoLayout};
@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use quick_error::ResultExt;
+use serde_json::{Map, Value, from_reader};
+
+use dedup::{DecodePackageFile, Error, PackageInfo, is_valid_package_name};
+
+/// The lockfiles npm writes, in the order of precedence npm gives them.
+const LOCKFILES: &[&str] = &["npm-shrinkwrap.json", "package-lock.json"];
+
+/// A package as npm recorded it within a lockfile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockedPackage {
+    /// The location the package is expected at.
+    pub info: PackageInfo,
+    /// The name of the package within the registry, which differs from the name of its directory
+    /// if the package was installed under an alias.
+    pub name: String,
+    /// The version of the package, or the specifier it was installed from in lockfiles of
+    /// version 1, like `github:user/repo#<commit>`.
+    pub version: String,
+    /// The location the package was downloaded from.
+    pub resolved: Option<String>,
+    /// The subresource integrity string of the downloaded tarball, like `sha512-...`.
+    pub integrity: Option<String>,
+}
+
+/// A difference between the packages within a lockfile and the packages on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockDiscrepancy {
+    /// The package was found on disk, but the lockfile doesn't know it.
+    NotInLock(PackageInfo),
+    /// The lockfile lists the package, but it wasn't found on disk.
+    NotOnDisk(LockedPackage),
+}
+
+impl LockDiscrepancy {
+    /// The directory of the package this discrepancy is about.
+    pub fn directory(&self) -> &Path {
+        match *self {
+            LockDiscrepancy::NotInLock(ref p) => &p.directory,
+            LockDiscrepancy::NotOnDisk(ref p) => &p.info.directory,
+        }
+    }
+}
+
+fn lockfile_error(lockfile: &Path, expectation: &str) -> Error {
+    Error::Lockfile(lockfile.to_owned(), expectation.to_owned())
+}
+
+fn string_field(entry: &Map<String, Value>, field_name: &str) -> Option<String> {
+    entry.get(field_name).and_then(Value::as_str).map(str::to_owned)
+}
+
+/// Returns the name of the package from the part of `key` after its last `node_modules/`.
+fn name_from_key(key: &str) -> &str {
+    key.rsplit_once("node_modules/").map(|(_, name)| name).unwrap_or(key)
+}
+
+/// Returns true if `key` is the location of a package within `node_modules`, like
+/// `node_modules/a/node_modules/@scope/b`, whose every name is a valid package name.
+fn is_valid_package_key(key: &str) -> bool {
+    key.strip_prefix("node_modules/")
+        .map(|names| names.split("/node_modules/").all(is_valid_package_name))
+        .unwrap_or(false)
+}
+
+fn validated_name(lockfile: &Path, name: String) -> Result<String, Error> {
+    if is_valid_package_name(&name) {
+        Ok(name)
+    } else {
+        Err(lockfile_error(lockfile, &format!("'{}' is no valid package name", name)))
+    }
+}
+
+/// Read the `packages` section of lockfiles of version 2 and later, which is keyed by the location
+/// of each package relative to the project.
+fn read_packages(lockfile: &Path,
+                 root: &Path,
+                 packages: &Map<String, Value>,
+                 out: &mut Vec<LockedPackage>)
+                 -> Result<(), Error> {
+    for (key, entry) in packages {
+        // The project itself has the empty key, and workspaces are located outside of node_modules
+        if !key.starts_with("node_modules/") {
+            continue;
+        }
+        if !is_valid_package_key(key) {
+            return Err(lockfile_error(lockfile, &format!("package '{}' is no valid location", key)));
+        }
+        let entry = entry.as_object().ok_or_else(|| lockfile_error(lockfile, "packages need to be objects"))?;
+        // Links are not followed when searching packages on disk either
+        if entry.get("link").and_then(Value::as_bool).unwrap_or(false) {
+            continue;
+        }
+        let directory = key.split('/').fold(root.to_path_buf(), |p, c| p.join(c));
+        let name = string_field(entry, "name").unwrap_or_else(|| name_from_key(key).to_owned());
+        out.push(LockedPackage {
+            name: validated_name(lockfile, name)?,
+            version: string_field(entry, "version")
+                .ok_or_else(|| lockfile_error(lockfile, &format!("package '{}' has no version", key)))?,
+            resolved: string_field(entry, "resolved"),
+            integrity: string_field(entry, "integrity"),
+            info: PackageInfo {
+                directory,
+                root_directory: root.join("node_modules"),
+            },
+        });
+    }
+    Ok(())
+}
+
+/// Read the `dependencies` section of lockfiles of version 1, in which the dependencies of each
+/// package are nested within it, just like the `node_modules` directories on disk.
+fn read_dependencies(lockfile: &Path,
+                     root: &Path,
+                     parent: &Path,
+                     dependencies: &Map<String, Value>,
+                     out: &mut Vec<LockedPackage>)
+                     -> Result<(), Error> {
+    for (name, entry) in dependencies {
+        let name = validated_name(lockfile, name.to_owned())?;
+        let entry = entry.as_object().ok_or_else(|| lockfile_error(lockfile, "dependencies need to be objects"))?;
+        let version = string_field(entry, "version")
+            .ok_or_else(|| lockfile_error(lockfile, &format!("dependency '{}' has no version", name)))?;
+        let directory = name.split('/').fold(parent.join("node_modules"), |p, c| p.join(c));
+        if let Some(nested) = entry.get("dependencies") {
+            let nested = nested.as_object()
+                .ok_or_else(|| lockfile_error(lockfile, "dependencies need to be an object"))?;
+            read_dependencies(lockfile, root, &directory, nested, out)?;
+        }
+        // Local directories are linked rather than installed
+        if version.starts_with("file:") {
+            continue;
+        }
+        // Aliases are recorded as `npm:<name>@<version>`
+        let (name, version) = match version.strip_prefix("npm:").and_then(|a| a.rsplit_once('@')) {
+            Some((name, version)) => (validated_name(lockfile, name.to_owned())?, version.to_owned()),
+            None => (name, version),
+        };
+        out.push(LockedPackage {
+            name,
+            version,
+            resolved: string_field(entry, "resolved"),
+            integrity: string_field(entry, "integrity"),
+            info: PackageInfo {
+                directory,
+                root_directory: root.join("node_modules"),
+            },
+        });
+    }
+    Ok(())
+}
+
+/// Read the packages recorded in the `npm-shrinkwrap.json` or `package-lock.json` of the project
+/// at `root`, the former taking precedence like it does for npm. Lockfiles of version 1 to 3 are
+/// supported.
+///
+/// Packages are returned in the order `find_packages(...)` would find them on disk, and the
+/// `root_directory` of each `PackageInfo` is `<root>/node_modules`. This allows using the
+/// lockfile as input for `deduplicate_into(...)` instead of scanning `node_modules`. Symbolic
+/// links and packages installed from local directories are left out, as are workspaces.
+/// Lockfiles locating packages anywhere but within `node_modules`, or naming them in a way which
+/// isn't a valid package name, fail with `Error::Lockfile`.
+pub fn find_locked_packages<P>(root: P) -> Result<Vec<LockedPackage>, Error>
+    where P: AsRef<Path>
+{
+    let root = root.as_ref();
+    let lockfile = LOCKFILES.iter()
+        .map(|name| root.join(name))
+        .find(|p| p.is_file())
+        .ok_or_else(|| {
+            lockfile_error(&root.join(LOCKFILES[1]), "it does not exist, and neither does npm-shrinkwrap.json")
+        })?;
+    let rd = fs::File::open(&lockfile).map_err(|err| lockfile_error(&lockfile, &err.to_string()))?;
+    let lock: Value = from_reader(rd).context(DecodePackageFile(&lockfile))?;
+    let lock = lock.as_object().ok_or_else(|| lockfile_error(&lockfile, "Top level was not an object"))?;
+
+    let mut packages = Vec::new();
+    match lock.get("lockfileVersion").map(|v| v.as_u64()) {
+        Some(Some(2)) | Some(Some(3)) if lock.contains_key("packages") => {
+            let section = lock["packages"]
+                .as_object()
+                .ok_or_else(|| lockfile_error(&lockfile, "'packages' needs to be an object"))?;
+            read_packages(&lockfile, root, section, &mut packages)?;
+        }
+        // Version 2 lockfiles may omit `packages`, and npm 5 writes no version at all
+        None | Some(Some(1)) | Some(Some(2)) => {
+            if let Some(section) = lock.get("dependencies") {
+                let section = section.as_object()
+                    .ok_or_else(|| lockfile_error(&lockfile, "'dependencies' needs to be an object"))?;
+                read_dependencies(&lockfile, root, root, section, &mut packages)?;
+            }
+        }
+        _ => return Err(lockfile_error(&lockfile, "'lockfileVersion' needs to be 1, 2 or 3")),
+    }
+    packages.sort_by(|a, b| a.info.directory.cmp(&b.info.directory));
+    Ok(packages)
+}
+
+/// Compare the packages of a lockfile as returned by `find_locked_packages(...)` with the packages
+/// found on disk, like the ones returned by `find_packages(...)`. Packages are identified by their
+/// directory, and the discrepancies are returned in the order of their directory.
+pub fn cross_check_lock<'a, I>(locked: &[LockedPackage], on_disk: I) -> Vec<LockDiscrepancy>
+    where I: IntoIterator<Item = &'a PackageInfo>
+{
+    let mut unmatched: BTreeMap<&Path, &LockedPackage> = locked.iter()
+        .map(|p| (p.info.directory.as_path(), p))
+        .collect();
+    let mut discrepancies: Vec<_> = on_disk.into_iter()
+        .filter(|p| unmatched.remove(p.directory.as_path()).is_none())
+        .map(|p| LockDiscrepancy::NotInLock(p.clone()))
+        .collect();
+    discrepancies.extend(unmatched.into_values().map(|p| LockDiscrepancy::NotOnDisk(p.clone())));
+    discrepancies.sort_by(|a, b| a.directory().cmp(b.directory()));
+    discrepancies
+}
@@ -0,0 +1,34 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Apply `f` to all `items` on up to `threads` threads, and return the results in the order of
+/// `items`. With less than two threads, everything happens on the calling thread.
+pub(crate) fn parallel_map<T, R, F>(items: &[T], threads: usize, f: F) -> Vec<R>
+    where T: Sync,
+          R: Send,
+          F: Fn(&T) -> R + Sync
+{
+    if threads < 2 || items.len() < 2 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<_>>());
+    thread::scope(|s| {
+        for _ in 0..threads.min(items.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= items.len() {
+                    break;
+                }
+                let r = f(&items[i]);
+                results.lock().expect("no worker to panic while holding the lock")[i] = Some(r);
+            });
+        }
+    });
+    results.into_inner()
+        .expect("no worker to panic while holding the lock")
+        .into_iter()
+        .map(|r| r.expect("every item to be processed"))
+        .collect()
+}
@@ -0,0 +1,109 @@
+use std::env;
+use std::fs;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use serde_json::Value;
+
+/// Returns an absolute version of `p`, which resolves all symbolic links in the part of `p` that
+/// exists already. The remainder is appended as is.
+pub(crate) fn absolute(p: &Path) -> PathBuf {
+    let p = if p.is_absolute() {
+        p.to_owned()
+    } else {
+        env::current_dir().map(|cwd| cwd.join(p)).unwrap_or_else(|_| p.to_owned())
+    };
+    let mut existing = p.as_path();
+    let mut remainder = Vec::new();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return remainder.iter().rev().fold(canonical, |path, c| path.join(c));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                remainder.push(name.to_owned());
+                existing = parent;
+            }
+            _ => return p,
+        }
+    }
+}
+
+/// Returns `p` as JSON string, or as array of its bytes if it is not valid UTF-8, which
+/// `path_from_json(...)` turns back into the very same path.
+pub(crate) fn path_to_json(p: &Path) -> Value {
+    match p.to_str() {
+        Some(s) => Value::String(s.to_owned()),
+        None => Value::Array(p.as_os_str().as_bytes().iter().map(|&b| Value::U64(u64::from(b))).collect()),
+    }
+}
+
+/// Parse a path as produced by `path_to_json(...)`.
+pub(crate) fn path_from_json(v: &Value) -> Option<PathBuf> {
+    match *v {
+        Value::String(ref s) => Some(PathBuf::from(s)),
+        Value::Array(ref bytes) => {
+            bytes.iter()
+                .map(|b| b.as_u64().filter(|&b| b <= 0xff).map(|b| b as u8))
+                .collect::<Option<Vec<_>>>()
+                .map(|bytes| PathBuf::from(OsStr::from_bytes(&bytes)))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the path to `target` relative to the directory `dir`, such that `dir.join(result)`
+/// points to `target`. Both paths are made absolute first.
+pub(crate) fn relative_to(dir: &Path, target: &Path) -> PathBuf {
+    let dir = absolute(dir);
+    let target = absolute(target);
+    let mut dir_components = dir.components().peekable();
+    let mut target_components = target.components().peekable();
+    while let (Some(d), Some(t)) = (dir_components.peek(), target_components.peek()) {
+        if d != t {
+            break;
+        }
+        dir_components.next();
+        target_components.next();
+    }
+    dir_components.map(|_| Component::ParentDir)
+        .chain(target_components)
+        .fold(PathBuf::new(), |path, c| path.join(c.as_os_str()))
+}
+
+/// Returns the path the symbolic link at `link` points to, which is relative to the directory
+/// containing `link` if the link itself is relative.
+pub(crate) fn resolve_link(link: &Path) -> io::Result<PathBuf> {
+    let target = fs::read_link(link)?;
+    Ok(match link.parent() {
+        Some(parent) if target.is_relative() => parent.join(target),
+        _ => target,
+    })
+}
+
+/// Returns true if `a` and `b` both exist and are the same file, for instance because one is a hard
+/// link to the other.
+pub(crate) fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Lock `file` exclusively, waiting for other processes to release it if `wait` is true.
+/// Otherwise, an error of kind `io::ErrorKind::WouldBlock` is returned if it is locked already.
+/// The lock is released as soon as the file is closed.
+pub(crate) fn lock_exclusively(file: &fs::File, wait: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let operation = if wait { libc::LOCK_EX } else { libc::LOCK_EX | libc::LOCK_NB };
+    // SAFETY: the file descriptor is valid and owned by `file`, which outlives the call
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -0,0 +1,319 @@
+use std::convert::Infallible;
+use std::error::Error as StdError;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as DeError;
+use serde_json::{Map, Value};
+
+use dedup::{Error, Instruction, InstructionOwned, PackageInfo, Visitor, fetch_string, read_package_json};
+use gc::register_project;
+use paths::{absolute, path_from_json, path_to_json};
+
+/// The version of the JSON format produced by `Plan::to_json()`.
+const PLAN_FORMAT: u64 = 2;
+
+/// A package an instruction of a plan refers to, as it was found when the plan was made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedPackage {
+    /// The directory containing the package.json, as it appears within the instruction.
+    pub directory: PathBuf,
+    pub name: String,
+    pub version: String,
+}
+
+impl PlannedPackage {
+    /// Returns the package found in `directory` right now, if its package.json has a name and a
+    /// version.
+    fn read(directory: &Path) -> Option<PlannedPackage> {
+        let info = PackageInfo {
+            directory: directory.to_owned(),
+            root_directory: directory.to_owned(),
+        };
+        let pj = read_package_json(&info).ok()?;
+        Some(PlannedPackage {
+            directory: directory.to_owned(),
+            name: fetch_string(&pj, &info, "name").ok()?,
+            version: fetch_string(&pj, &info, "version").ok()?,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        let mut m = Map::new();
+        m.insert(String::from("directory"), path_to_json(&self.directory));
+        m.insert(String::from("name"), Value::String(self.name.clone()));
+        m.insert(String::from("version"), Value::String(self.version.clone()));
+        Value::Object(m)
+    }
+
+    fn from_json(v: &Value) -> Result<PlannedPackage, Error> {
+        let field = |name: &str| match v.find(name) {
+            Some(Value::String(s)) => Ok(s.clone()),
+            _ => Err(Error::InvalidPlan(format!("Package field '{}' must be a string", name))),
+        };
+        let directory = v.find("directory")
+            .and_then(path_from_json)
+            .ok_or_else(|| Error::InvalidPlan(String::from("Package field 'directory' must be a path")))?;
+        Ok(PlannedPackage {
+            directory,
+            name: field("name")?,
+            version: field("version")?,
+        })
+    }
+}
+
+/// All instructions produced for a project, along with the context they were produced in, which
+/// allows to review them before applying them later via `apply_plan(...)`, possibly on another
+/// machine.
+///
+/// A `Plan` is a `Visitor` which records every instruction it is handed without changing anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// The repository the instructions refer to.
+    pub repo: PathBuf,
+    /// The directory containing the `node_modules` directory the instructions refer to.
+    pub project: PathBuf,
+    /// The time at which the plan was created, in seconds since the UNIX epoch.
+    pub created_at: u64,
+    pub instructions: Vec<InstructionOwned>,
+    /// The packages found at the paths of the instructions when they were recorded, which
+    /// `validate()` expects to still be there.
+    pub packages: Vec<PlannedPackage>,
+}
+
+impl Plan {
+    /// Returns an empty plan for `project` and `repo`, created right now.
+    pub fn new<R, P>(repo: R, project: P) -> Plan
+        where R: AsRef<Path>,
+              P: AsRef<Path>
+    {
+        Plan {
+            repo: absolute(repo.as_ref()),
+            project: absolute(project.as_ref()),
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            instructions: Vec::new(),
+            packages: Vec::new(),
+        }
+    }
+
+    /// Returns a JSON object with all metadata, and the instructions in the order they are to
+    /// be applied, each in the form produced by `Instruction::to_json()`. Paths which are not
+    /// valid UTF-8 are stored as array of their bytes. This is also the form `Plan` is serialized
+    /// to and deserialized from via serde.
+    pub fn to_json(&self) -> Value {
+        let mut m = Map::new();
+        m.insert(String::from("format"), Value::U64(PLAN_FORMAT));
+        m.insert(String::from("repo"), path_to_json(&self.repo));
+        m.insert(String::from("project"), path_to_json(&self.project));
+        m.insert(String::from("created_at"), Value::U64(self.created_at));
+        m.insert(String::from("instructions"),
+                 Value::Array(self.instructions.iter().map(|i| i.as_instruction().to_json()).collect()));
+        m.insert(String::from("packages"),
+                 Value::Array(self.packages.iter().map(PlannedPackage::to_json).collect()));
+        Value::Object(m)
+    }
+
+    /// Parse a plan from a JSON object as produced by `to_json()`.
+    pub fn from_json(v: &Value) -> Result<Plan, Error> {
+        let m = match *v {
+            Value::Object(ref m) => m,
+            _ => return Err(Error::InvalidPlan(String::from("A plan must be an object"))),
+        };
+        match m.get("format") {
+            Some(&Value::U64(PLAN_FORMAT)) => {}
+            _ => return Err(Error::InvalidPlan(format!("Only plans of format {} are supported", PLAN_FORMAT))),
+        }
+        let path = |field: &str| {
+            m.get(field)
+                .and_then(path_from_json)
+                .ok_or_else(|| Error::InvalidPlan(format!("Plan field '{}' must be a path", field)))
+        };
+        let created_at = match m.get("created_at") {
+            Some(&Value::U64(t)) => t,
+            _ => return Err(Error::InvalidPlan(String::from("Plan field 'created_at' must be a number"))),
+        };
+        let instructions = match m.get("instructions") {
+            Some(Value::Array(instructions)) => {
+                instructions.iter().map(InstructionOwned::from_json).collect::<Result<Vec<_>, _>>()?
+            }
+            _ => return Err(Error::InvalidPlan(String::from("Plan field 'instructions' must be an array"))),
+        };
+        let packages = match m.get("packages") {
+            Some(Value::Array(packages)) => {
+                packages.iter().map(PlannedPackage::from_json).collect::<Result<Vec<_>, _>>()?
+            }
+            _ => return Err(Error::InvalidPlan(String::from("Plan field 'packages' must be an array"))),
+        };
+        Ok(Plan {
+            repo: path("repo")?,
+            project: path("project")?,
+            created_at,
+            instructions,
+            packages,
+        })
+    }
+
+    /// Check whether the filesystem still matches the assumptions each instruction was made under,
+    /// and return one error per mismatch. This includes the name and version of each package
+    /// in `packages`, which would otherwise be moved to the location of another version.
+    ///
+    /// Paths within locations which are changed by a previous instruction of the plan are not
+    /// checked, as their state depends on the plan being applied in order.
+    pub fn validate(&self) -> Vec<Error> {
+        let mut errors = Vec::new();
+        let mut changed: Vec<&Path> = Vec::new();
+        for instruction in &self.instructions {
+            let (_, paths) = instruction.as_instruction().fields();
+            let mut check = |p: &Path, holds: bool, expectation: &str| {
+                if !holds && !changed.iter().any(|c| p.starts_with(c)) {
+                    errors.push(Error::StalePlan(p.to_owned(), expectation.to_owned()));
+                }
+            };
+            match *instruction {
+                InstructionOwned::MoveAndSymlink { ref from_here, ref to_here, .. } |
+                InstructionOwned::MoveWithinRepo { ref from_here, ref to_here } => {
+                    check(from_here, is_directory(from_here), "expected a directory");
+                    check(to_here, !exists(to_here), "expected nothing to exist");
+                }
+                InstructionOwned::ReplaceWithSymlink { ref this_directory, ref symlink_destination } => {
+                    check(this_directory, is_directory(this_directory), "expected a directory");
+                    let destination = link_target(this_directory, symlink_destination);
+                    check(&destination, destination.is_dir(), "expected a directory");
+                }
+                InstructionOwned::CopyBackFromRepo { ref this_symlink, ref from_here } => {
+                    check(this_symlink, is_symlink(this_symlink), "expected a symbolic link");
+                    check(from_here, from_here.is_dir(), "expected a directory");
+                }
+                InstructionOwned::RemoveFromRepo { ref this_directory } => {
+                    check(this_directory, is_directory(this_directory), "expected a directory");
+                }
+                InstructionOwned::RemoveSymlink { ref this_symlink } => {
+                    check(this_symlink, is_symlink(this_symlink), "expected a symbolic link");
+                }
+                InstructionOwned::RetargetSymlink { ref this_symlink, ref symlink_destination } => {
+                    check(this_symlink, is_symlink(this_symlink), "expected a symbolic link");
+                    let destination = link_target(this_symlink, symlink_destination);
+                    check(&destination, destination.is_dir(), "expected a directory");
+                }
+                InstructionOwned::CreateSymlink { ref this_symlink, ref symlink_destination } => {
+                    check(this_symlink, !exists(this_symlink), "expected nothing to exist");
+                    // The directory containing the link may not exist yet
+                    let destination = normalized(&link_target(this_symlink, symlink_destination));
+                    check(&destination, destination.is_dir(), "expected a directory");
+                }
+                InstructionOwned::HardlinkFiles { ref from_here, .. } |
+                InstructionOwned::CloneFiles { ref from_here, .. } => {
+                    check(from_here, is_directory(from_here), "expected a directory");
+                }
+            }
+            // Missing paths were reported above already
+            for &(_, p) in &paths {
+                if let Some(planned) = self.packages.iter().find(|k| k.directory == p) {
+                    check(p,
+                          !exists(p) || PlannedPackage::read(p).as_ref() == Some(planned),
+                          &format!("expected package {}@{}", planned.name, planned.version));
+                }
+            }
+            changed.extend(paths.into_iter().filter(|&(name, _)| name != "symlink_destination").map(|(_, p)| p));
+        }
+        errors
+    }
+}
+
+fn exists(p: &Path) -> bool {
+    p.symlink_metadata().is_ok()
+}
+
+fn is_symlink(p: &Path) -> bool {
+    p.symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(false)
+}
+
+fn is_directory(p: &Path) -> bool {
+    p.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false)
+}
+
+/// Returns the path the symbolic link at `link` would point to if its destination was `destination`.
+fn link_target(link: &Path, destination: &Path) -> PathBuf {
+    link.parent().map(|parent| parent.join(destination)).unwrap_or_else(|| destination.to_owned())
+}
+
+/// Returns `p` without `..` components, which are resolved lexically.
+fn normalized(p: &Path) -> PathBuf {
+    p.components().fold(PathBuf::new(), |mut normalized, c| {
+        match c {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c.as_os_str()),
+        }
+        normalized
+    })
+}
+
+impl Visitor for Plan {
+    type Error = Infallible;
+
+    fn error(&mut self, _package: &PackageInfo, _err: &Error) {}
+
+    fn change(&mut self, instruction: Instruction) -> Result<(), Self::Error> {
+        let (_, paths) = instruction.fields();
+        for (name, p) in paths {
+            if name != "symlink_destination" && !self.packages.iter().any(|k| k.directory == p) {
+                self.packages.extend(PlannedPackage::read(p));
+            }
+        }
+        self.instructions.push(instruction.into());
+        Ok(())
+    }
+}
+
+impl Serialize for Plan {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer
+    {
+        self.to_json().serialize(serializer)
+    }
+}
+
+impl Deserialize for Plan {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error>
+        where D: Deserializer
+    {
+        let v = Value::deserialize(deserializer)?;
+        Plan::from_json(&v).map_err(|err| D::Error::custom(err.to_string()))
+    }
+}
+
+/// Hand all instructions of `plan` to `visitor` in order, but only if `plan.validate()` finds the
+/// filesystem to still match the plan. Otherwise, nothing is handed to the visitor and all
+/// mismatches are returned. The project of the plan is registered as user of its repository
+/// before the first instruction is handed out, see `register_project(...)`.
+///
+/// Applying stops at the first instruction the visitor fails to apply, as later instructions may
+/// depend on it.
+pub fn apply_plan<V, E>(plan: &Plan, visitor: &mut V) -> Result<(), Vec<Error>>
+    where E: StdError + 'static,
+          V: Visitor<Error = E>
+{
+    let errors = plan.validate();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    register_project(&plan.repo, &plan.project).map_err(|err| vec![err])?;
+
+    for instruction in &plan.instructions {
+        if let Err(err) = visitor.change(instruction.as_instruction()) {
+            let (_, paths) = instruction.as_instruction().fields();
+            let p = paths[0].1.to_owned();
+            let err = Error::Visitor(p.clone(), Box::new(err));
+            visitor.error(&PackageInfo {
+                              directory: p,
+                              root_directory: plan.project.join("node_modules"),
+                          },
+                          &err);
+            return Err(vec![err]);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::env::consts;
+use std::fs;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+/// Determines which packages are stored in a platform specific location within the repository,
+/// that is `<name>/<version>/<platform>` instead of `<name>/<version>`.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub enum PlatformSegment {
+    /// No package is considered platform specific. This is the right choice if the repository is
+    /// not shared by multiple platforms.
+    #[default]
+    Never,
+    /// All packages are stored per platform.
+    Always,
+    /// Packages are considered platform specific if they look like native addons, i.e. they have a
+    /// `binding.gyp` file, contain compiled `.node` files, or set `gypfile` in their package.json.
+    Detect,
+    /// Only the packages with the given names are stored per platform.
+    Packages(Vec<String>),
+}
+
+impl PlatformSegment {
+    /// Returns true if the package named `name` should be stored per platform. `is_native` is
+    /// only consulted in `Detect` mode.
+    pub fn applies_to<F>(&self, name: &str, is_native: F) -> bool
+        where F: FnOnce() -> bool
+    {
+        match *self {
+            PlatformSegment::Never => false,
+            PlatformSegment::Always => true,
+            PlatformSegment::Detect => is_native(),
+            PlatformSegment::Packages(ref names) => names.iter().any(|n| n == name),
+        }
+    }
+}
+
+/// Returns the identifier of the platform we are running on, like `linux-x86_64`.
+pub fn current_platform() -> String {
+    format!("{}-{}", consts::OS, consts::ARCH)
+}
+
+fn contains_compiled_addon(dir: &Path) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(Result::ok).any(|entry| {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(ref t) if t.is_dir() => entry.file_name() != "node_modules" && contains_compiled_addon(&path),
+            Ok(ref t) if t.is_file() => path.extension().is_some_and(|ext| ext == "node"),
+            _ => false,
+        }
+    })
+}
+
+/// Returns true if the package in `dir` with the parsed `package_json` looks like a native addon.
+pub(crate) fn is_native_package(dir: &Path, package_json: &Map<String, Value>) -> bool {
+    package_json.get("gypfile").and_then(Value::as_bool).unwrap_or(false) ||
+    dir.join("binding.gyp").is_file() || contains_compiled_addon(dir)
+}
@@ -0,0 +1,379 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use semver::{Identifier, Version};
+
+quick_error!{
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum RangeParseError {
+        InvalidComparator(comparator: String) {
+            description("A comparator within a version range could not be parsed")
+            display("Invalid comparator '{}'", comparator)
+        }
+        InvalidAliasName(name: String) {
+            description("The package an alias refers to has no valid name")
+            display("Invalid package name '{}' of alias", name)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+}
+
+impl Op {
+    fn as_str(self) -> &'static str {
+        match self {
+            Op::Less => "<",
+            Op::LessOrEqual => "<=",
+            Op::Greater => ">",
+            Op::GreaterOrEqual => ">=",
+            // node-semver omits the operator of exact versions
+            Op::Equal => "",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Comparator {
+    op: Op,
+    version: Version,
+}
+
+impl Comparator {
+    fn new(op: Op, major: u64, minor: u64, patch: u64, pre: Vec<Identifier>) -> Comparator {
+        Comparator {
+            op,
+            version: Version {
+                major,
+                minor,
+                patch,
+                pre,
+                build: Vec::new(),
+            },
+        }
+    }
+
+    /// An upper bound which excludes all prereleases of `major.minor.patch` as well.
+    fn below(major: u64, minor: u64, patch: u64) -> Comparator {
+        Comparator::new(Op::Less, major, minor, patch, vec![Identifier::Numeric(0)])
+    }
+
+    /// The comparator nothing satisfies, which is `<0.0.0-0`.
+    fn none() -> Comparator {
+        Comparator::below(0, 0, 0)
+    }
+
+    fn is_none(&self) -> bool {
+        *self == Comparator::none()
+    }
+
+    /// Returns true for `>=0.0.0`, which is satisfied by every version but prereleases.
+    fn is_any(&self) -> bool {
+        *self == Comparator::new(Op::GreaterOrEqual, 0, 0, 0, Vec::new())
+    }
+
+    fn matches(&self, v: &Version) -> bool {
+        let ordering = v.cmp(&self.version);
+        match self.op {
+            Op::Less => ordering == Ordering::Less,
+            Op::LessOrEqual => ordering != Ordering::Greater,
+            Op::Greater => ordering == Ordering::Greater,
+            Op::GreaterOrEqual => ordering != Ordering::Less,
+            Op::Equal => ordering == Ordering::Equal,
+        }
+    }
+}
+
+impl fmt::Display for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.op.as_str(), self.version)
+    }
+}
+
+/// A version which may lack trailing components, or use `x`, `X` or `*` for them. All components
+/// after the first missing one are ignored.
+struct Partial {
+    major: Option<u64>,
+    minor: Option<u64>,
+    patch: Option<u64>,
+    pre: Vec<Identifier>,
+}
+
+/// The highest version component node-semver accepts, which is the highest integer a JavaScript
+/// number represents exactly. This also keeps the bounds computed from components from overflowing.
+const MAX_COMPONENT: u64 = (1 << 53) - 1;
+
+fn parse_number(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) || (s.len() > 1 && s.starts_with('0')) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+fn parse_identifiers(s: &str) -> Option<Vec<Identifier>> {
+    s.split('.')
+        .map(|i| {
+            if i.is_empty() || !i.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+                None
+            } else if i.bytes().all(|b| b.is_ascii_digit()) {
+                parse_number(i).map(Identifier::Numeric)
+            } else {
+                Some(Identifier::AlphaNumeric(i.to_owned()))
+            }
+        })
+        .collect()
+}
+
+impl Partial {
+    fn parse(s: &str) -> Option<Partial> {
+        let s = s.trim_start_matches(['v', '=']);
+        let (s, build) = match s.find('+') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        if let Some(build) = build {
+            parse_identifiers(build)?;
+        }
+        let (core, pre) = match s.find('-') {
+            Some(i) => (&s[..i], Some(parse_identifiers(&s[i + 1..])?)),
+            None => (s, None),
+        };
+        let mut components = Vec::new();
+        for c in core.split('.') {
+            components.push(match c {
+                "x" | "X" | "*" => None,
+                c => Some(parse_number(c).filter(|&n| n <= MAX_COMPONENT)?),
+            });
+        }
+        // Prereleases can only be attached to complete versions
+        if components.len() > 3 || (pre.is_some() && components.len() < 3) {
+            return None;
+        }
+        if let Some(wildcard) = components.iter().position(Option::is_none) {
+            components.truncate(wildcard);
+        }
+        let component = |i: usize| components.get(i).cloned().unwrap_or(None);
+        Some(Partial {
+            major: component(0),
+            minor: component(1),
+            patch: component(2),
+            pre: pre.unwrap_or_default(),
+        })
+    }
+}
+
+/// Desugar `~partial`, which allows patch-level changes if a minor version is given, and
+/// minor-level changes otherwise.
+fn tilde(p: Partial, out: &mut Vec<Comparator>) {
+    match (p.major, p.minor, p.patch) {
+        (None, _, _) => {}
+        (Some(major), None, _) => {
+            out.push(Comparator::new(Op::GreaterOrEqual, major, 0, 0, Vec::new()));
+            out.push(Comparator::below(major + 1, 0, 0));
+        }
+        (Some(major), Some(minor), patch) => {
+            out.push(Comparator::new(Op::GreaterOrEqual, major, minor, patch.unwrap_or(0), p.pre));
+            out.push(Comparator::below(major, minor + 1, 0));
+        }
+    }
+}
+
+/// Desugar `^partial`, which allows changes that do not modify the left-most non-zero component.
+fn caret(p: Partial, out: &mut Vec<Comparator>) {
+    match (p.major, p.minor, p.patch) {
+        (None, _, _) => {}
+        (Some(major), None, _) => {
+            out.push(Comparator::new(Op::GreaterOrEqual, major, 0, 0, Vec::new()));
+            out.push(Comparator::below(major + 1, 0, 0));
+        }
+        (Some(major), Some(minor), patch) => {
+            out.push(Comparator::new(Op::GreaterOrEqual, major, minor, patch.unwrap_or(0), p.pre));
+            out.push(match (major, minor, patch) {
+                (0, 0, Some(patch)) => Comparator::below(0, 0, patch + 1),
+                (0, minor, _) => Comparator::below(0, minor + 1, 0),
+                (major, _, _) => Comparator::below(major + 1, 0, 0),
+            });
+        }
+    }
+}
+
+/// Desugar `op partial`, where missing components are wildcards.
+fn x_range(op: Op, p: Partial, out: &mut Vec<Comparator>) {
+    let major = match p.major {
+        Some(major) => major,
+        None => {
+            if op != Op::GreaterOrEqual && op != Op::LessOrEqual && op != Op::Equal {
+                out.push(Comparator::none());
+            }
+            return;
+        }
+    };
+    match (op, p.minor, p.patch) {
+        (op, Some(minor), Some(patch)) => out.push(Comparator::new(op, major, minor, patch, p.pre)),
+        (Op::Equal, None, _) => {
+            out.push(Comparator::new(Op::GreaterOrEqual, major, 0, 0, Vec::new()));
+            out.push(Comparator::below(major + 1, 0, 0));
+        }
+        (Op::Equal, Some(minor), None) => {
+            out.push(Comparator::new(Op::GreaterOrEqual, major, minor, 0, Vec::new()));
+            out.push(Comparator::below(major, minor + 1, 0));
+        }
+        (Op::Greater, None, _) => out.push(Comparator::new(Op::GreaterOrEqual, major + 1, 0, 0, Vec::new())),
+        (Op::Greater, Some(minor), None) => {
+            out.push(Comparator::new(Op::GreaterOrEqual, major, minor + 1, 0, Vec::new()))
+        }
+        (Op::LessOrEqual, None, _) => out.push(Comparator::below(major + 1, 0, 0)),
+        (Op::LessOrEqual, Some(minor), None) => out.push(Comparator::below(major, minor + 1, 0)),
+        (Op::GreaterOrEqual, minor, _) => {
+            out.push(Comparator::new(Op::GreaterOrEqual, major, minor.unwrap_or(0), 0, Vec::new()))
+        }
+        (Op::Less, minor, _) => out.push(Comparator::below(major, minor.unwrap_or(0), 0)),
+    }
+}
+
+/// Desugar the hyphen range `from - to`, which includes both ends. A partial `to` includes all
+/// versions its components match.
+fn hyphen(from: Partial, to: Partial, out: &mut Vec<Comparator>) {
+    if let Some(major) = from.major {
+        out.push(Comparator::new(Op::GreaterOrEqual,
+                                 major,
+                                 from.minor.unwrap_or(0),
+                                 from.patch.unwrap_or(0),
+                                 from.pre));
+    }
+    match (to.major, to.minor, to.patch) {
+        (None, _, _) => {}
+        (Some(major), None, _) => out.push(Comparator::below(major + 1, 0, 0)),
+        (Some(major), Some(minor), None) => out.push(Comparator::below(major, minor + 1, 0)),
+        (Some(major), Some(minor), Some(patch)) => {
+            out.push(Comparator::new(Op::LessOrEqual, major, minor, patch, to.pre))
+        }
+    }
+}
+
+fn parse_comparator(token: &str, out: &mut Vec<Comparator>) -> Result<(), RangeParseError> {
+    let invalid = || RangeParseError::InvalidComparator(token.to_owned());
+    let partial = |s: &str| Partial::parse(s).ok_or_else(invalid);
+    if let Some(rest) = token.strip_prefix("~>").or_else(|| token.strip_prefix('~')) {
+        tilde(partial(rest)?, out);
+    } else if let Some(rest) = token.strip_prefix('^') {
+        caret(partial(rest)?, out);
+    } else {
+        let (op, rest) = [(">=", Op::GreaterOrEqual), ("<=", Op::LessOrEqual), (">", Op::Greater),
+                          ("<", Op::Less), ("=", Op::Equal)]
+            .iter()
+            .find_map(|&(prefix, op)| token.strip_prefix(prefix).map(|rest| (op, rest)))
+            .unwrap_or((Op::Equal, token));
+        x_range(op, partial(rest)?, out);
+    }
+    Ok(())
+}
+
+/// Parse the comparators of a range without `||`, which are all satisfied by the same version.
+/// An empty result is satisfied by every version but prereleases.
+fn parse_set(set: &str) -> Result<Vec<Comparator>, RangeParseError> {
+    let tokens: Vec<_> = set.split_whitespace().collect();
+    let mut comparators = Vec::new();
+    if tokens.len() == 3 && tokens[1] == "-" {
+        let partial = |s: &str| Partial::parse(s).ok_or_else(|| RangeParseError::InvalidComparator(s.to_owned()));
+        hyphen(partial(tokens[0])?, partial(tokens[2])?, &mut comparators);
+    } else {
+        let mut tokens = tokens.into_iter();
+        while let Some(token) = tokens.next() {
+            // Operators may be separated from their version by whitespace
+            let token = if token.bytes().all(|b| b"<>=~^".contains(&b)) {
+                format!("{}{}", token, tokens.next().unwrap_or(""))
+            } else {
+                token.to_owned()
+            };
+            parse_comparator(&token, &mut comparators)?;
+        }
+    }
+
+    if let Some(none) = comparators.iter().find(|c| c.is_none()) {
+        return Ok(vec![none.clone()]);
+    }
+    let mut unique: Vec<Comparator> = Vec::with_capacity(comparators.len());
+    for c in comparators {
+        if !c.is_any() && !unique.contains(&c) {
+            unique.push(c);
+        }
+    }
+    Ok(unique)
+}
+
+/// A range of versions, which follows the grammar and semantics of node-semver, the library npm
+/// uses to select versions.
+///
+/// This includes hyphen ranges like `1.2.3 - 2.x`, unions like `1.x || >=2.5.0`, wildcards in
+/// any position, and the tilde and caret operators. Like npm, a prerelease version only satisfies
+/// the range if one of its comparators refers to a prerelease of the same `major.minor.patch`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VersionRange {
+    sets: Vec<Vec<Comparator>>,
+}
+
+impl VersionRange {
+    /// Parse `range` in the strict mode of node-semver, which rejects leading zeros and
+    /// prereleases not separated by a hyphen.
+    pub fn parse(range: &str) -> Result<VersionRange, RangeParseError> {
+        let mut sets = range.split("||").map(parse_set).collect::<Result<Vec<_>, _>>()?;
+        if sets.len() > 1 {
+            let first = sets[0].clone();
+            sets.retain(|s| !(s.len() == 1 && s[0].is_none()));
+            if sets.is_empty() {
+                sets.push(first);
+            } else if sets.iter().any(|s| s.is_empty()) {
+                sets = vec![Vec::new()];
+            }
+        }
+        Ok(VersionRange { sets })
+    }
+
+    /// Returns true if `version` is within this range.
+    pub fn satisfies(&self, version: &Version) -> bool {
+        self.sets.iter().any(|set| {
+            set.iter().all(|c| c.matches(version)) &&
+            (version.pre.is_empty() ||
+             set.iter().any(|c| {
+                !c.version.pre.is_empty() &&
+                (c.version.major, c.version.minor, c.version.patch) ==
+                (version.major, version.minor, version.patch)
+            }))
+        })
+    }
+
+    /// Returns the highest of `versions` which is within this range.
+    pub fn max_satisfying<'a, I>(&self, versions: I) -> Option<&'a Version>
+        where I: IntoIterator<Item = &'a Version>
+    {
+        versions.into_iter().filter(|v| self.satisfies(v)).max()
+    }
+}
+
+/// Formats the range like node-semver, with all operators desugared into plain comparisons.
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, set) in self.sets.iter().enumerate() {
+            if i > 0 {
+                f.write_str("||")?;
+            }
+            if set.is_empty() {
+                f.write_str("*")?;
+            }
+            for (j, c) in set.iter().enumerate() {
+                if j > 0 {
+                    f.write_str(" ")?;
+                }
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+
+use semver::Version;
+
+use layout::RepoLayout;
+use range::VersionRange;
+use paths::{absolute, resolve_link};
+
+/// Returns the highest version of package `name` within `repo` which satisfies `req`.
+pub(crate) fn best_repo_version(repo: &Path,
+                                layout: &dyn RepoLayout,
+                                name: &str,
+                                req: &VersionRange)
+                                -> Option<Version> {
+    layout.versions(repo, name).into_iter().filter(|v| req.satisfies(v)).max()
+}
+
+/// A symbolic link pointing to a version of a package within the repository.
+pub(crate) struct LinkedVersion {
+    pub version: Version,
+    /// The platform directory below the version directory, if the link points to one.
+    pub platform: Option<OsString>,
+}
+
+/// Returns the version of package `name` within `repo` the symbolic link at `link` points to, or
+/// `None` if `link` is no symbolic link or points elsewhere.
+pub(crate) fn linked_repo_version(link: &Path,
+                                  repo: &Path,
+                                  layout: &dyn RepoLayout,
+                                  name: &str)
+                                  -> Option<LinkedVersion> {
+    if !link.symlink_metadata().ok()?.file_type().is_symlink() {
+        return None;
+    }
+    let target = absolute(&resolve_link(link).ok()?);
+    let (version, rest) = layout.locate(&absolute(repo), name, &target)?;
+    let mut components = rest.components();
+    let platform = match (components.next(), components.next()) {
+        (None, _) => None,
+        (Some(Component::Normal(p)), None) => Some(p.to_owned()),
+        _ => return None,
+    };
+    Some(LinkedVersion { version, platform })
+}
+
+/// Returns the location within `repo` the symbolic link at `link` for dependency `name` with
+/// requirement `req` should point to, if it currently points to a version which is lower than
+/// the best one available, or which doesn't satisfy `req`.
+pub(crate) fn retarget_destination(link: &Path,
+                                   repo: &Path,
+                                   layout: &dyn RepoLayout,
+                                   name: &str,
+                                   req: &VersionRange)
+                                   -> Option<PathBuf> {
+    let linked = linked_repo_version(link, repo, layout, name)?;
+    let best = best_repo_version(repo, layout, name, req)?;
+    if req.satisfies(&linked.version) && linked.version >= best {
+        return None;
+    }
+    let mut destination = layout.package_directory(repo, name, &best);
+    if let Some(platform) = linked.platform {
+        destination.push(platform);
+    }
+    if destination.is_dir() {
+        Some(destination)
+    } else {
+        None
+    }
+}
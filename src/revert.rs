@@ -0,0 +1,102 @@
+use std::error::Error as StdError;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use dedup::{Error, Instruction, PackageInfo, Visitor, read_package_json};
+use walk::find_repo_links;
+
+/// Returns true if `link` is located in the `node_modules` directory of a package and named after
+/// one of its peer dependencies, which npm never installs there. Within the repository, such links
+/// were created by deduplication to provide the package with its peers, and are not part of the
+/// project.
+fn is_provided_peer(link: &Path) -> bool {
+    let (name, node_modules) = match (link.parent(), link.file_name().and_then(|n| n.to_str())) {
+        (Some(parent), Some(name)) => {
+            match parent.file_name().and_then(|n| n.to_str()) {
+                Some(scope) if scope.starts_with('@') => (format!("{}/{}", scope, name), parent.parent()),
+                _ => (name.to_owned(), Some(parent)),
+            }
+        }
+        _ => return false,
+    };
+    let directory = match node_modules.and_then(Path::parent) {
+        Some(directory) => directory.to_owned(),
+        None => return false,
+    };
+    let pj = match read_package_json(&PackageInfo {
+        directory: directory.clone(),
+        root_directory: directory,
+    }) {
+        Ok(pj) => pj,
+        Err(_) => return false,
+    };
+    let declared = |key: &str| pj.get(key).and_then(Value::as_object).is_some_and(|deps| deps.contains_key(&name));
+    declared("peerDependencies") && !declared("dependencies") && !declared("optionalDependencies")
+}
+
+/// Walk the `node_modules` directory of the project at `root` and instruct `visitor` to replace
+/// every symbolic link pointing into `repo` with a copy of the package it points to, by means of
+/// `Instruction::CopyBackFromRepo`.
+///
+/// This restores a `node_modules` directory npm can operate on naturally, and which can be
+/// deduplicated again afterwards.
+/// Links are reported depth-first, thus links within linked packages are reported after the
+/// link they are contained in, and must be applied in order. Links to peer dependencies which
+/// deduplication added to packages within the repository are removed from the copies by means of
+/// `Instruction::RemoveSymlink` instead, and links below them are skipped. Once all instructions
+/// were applied, the project should be removed from the users of
+/// `repo` with `unregister_project(...)`.
+///
+/// Projects deduplicated with hard links or clones contain no links into the repository, and
+/// can't be reverted.
+pub fn revert<P, R, V, E>(root: P, repo: R, visitor: &mut V) -> Result<(), Vec<Error>>
+    where P: AsRef<Path>,
+          R: AsRef<Path>,
+          E: StdError + 'static,
+          V: Visitor<Error = E>
+{
+    let root_directory = root.as_ref().join("node_modules");
+    let package = |directory: &Path| {
+        PackageInfo {
+            directory: directory.to_owned(),
+            root_directory: root_directory.clone(),
+        }
+    };
+
+    let mut errors = Vec::new();
+    let links = find_repo_links(&root_directory, repo.as_ref(), &mut errors);
+    for err in &errors {
+        if let Error::ReadDirectory(ref p, _) = *err {
+            visitor.error(&package(p), err);
+        }
+    }
+
+    let mut skipped: Vec<PathBuf> = Vec::new();
+    for (i, link) in links.iter().enumerate() {
+        if skipped.iter().any(|s| link.link.starts_with(s)) {
+            continue;
+        }
+        let within_repo = links[..i].iter().any(|outer| link.link.starts_with(&outer.link));
+        let instruction = if within_repo && is_provided_peer(&link.link) {
+            skipped.push(link.link.clone());
+            Instruction::RemoveSymlink { this_symlink: &link.link }
+        } else {
+            Instruction::CopyBackFromRepo {
+                this_symlink: &link.link,
+                from_here: &link.target,
+            }
+        };
+        if let Err(err) = visitor.change(instruction) {
+            let err = Error::Visitor(link.link.clone(), Box::new(err));
+            visitor.error(&package(&link.link), &err);
+            errors.push(err);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
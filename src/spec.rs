@@ -0,0 +1,120 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use dedup::is_valid_package_name;
+use range::{RangeParseError, VersionRange};
+
+/// Prefixes of dependency specifiers which refer to a git repository.
+const GIT_PREFIXES: &[&str] = &["git:", "git+", "github:", "gitlab:", "bitbucket:", "gist:"];
+
+/// The way a dependency is specified within package.json, which determines where the dependency
+/// is installed from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DependencySpec {
+    /// A range of versions of the package within the registry, like `^1.2.0`.
+    Range(VersionRange),
+    /// A dist-tag of the package within the registry, like `latest`.
+    Tag(String),
+    /// Another package within the registry, installed under the name of the dependency, like
+    /// `npm:other@^1.0.0`.
+    Alias {
+        name: String,
+        spec: Box<DependencySpec>,
+    },
+    /// A git repository, like `git+https://example.com/repo.git#v1.0.0` or `user/repo`.
+    Git(String),
+    /// A local directory or tarball, like `file:../lib` or `./lib`.
+    File(PathBuf),
+    /// A tarball to download, like `https://example.com/package.tgz`.
+    Url(String),
+}
+
+/// Returns true if `tag` can be a dist-tag, which npm requires not to be a version range.
+fn is_tag(tag: &str) -> bool {
+    tag.starts_with(|c: char| c.is_ascii_alphabetic()) &&
+    tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+}
+
+/// Returns true if `spec` is the `<user>/<repository>` shorthand for a repository on GitHub,
+/// optionally followed by `#<reference>`.
+fn is_github_shorthand(spec: &str) -> bool {
+    let repository = spec.split('#').next().unwrap_or(spec);
+    let mut parts = repository.split('/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(user), Some(name), None) => {
+            [user, name].iter().all(|p| {
+                !p.is_empty() && !p.starts_with('.') &&
+                p.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+            })
+        }
+        _ => false,
+    }
+}
+
+impl DependencySpec {
+    /// Classify the version specifier `spec` of a dependency. Specifiers which don't fit any
+    /// other form are parsed as version range, whose error is returned if that fails. Aliases fail
+    /// with `RangeParseError::InvalidAliasName` unless they refer to a valid package name.
+    pub fn parse(spec: &str) -> Result<DependencySpec, RangeParseError> {
+        let spec = spec.trim();
+        if let Some(aliased) = spec.strip_prefix("npm:") {
+            // The name of a scoped package starts with '@' itself
+            let (name, spec) = match aliased[1.min(aliased.len())..].find('@') {
+                Some(at) => (&aliased[..at + 1], DependencySpec::parse(&aliased[at + 2..])?),
+                None => (aliased, DependencySpec::Tag(String::from("latest"))),
+            };
+            if !is_valid_package_name(name) {
+                return Err(RangeParseError::InvalidAliasName(name.to_owned()));
+            }
+            return Ok(DependencySpec::Alias {
+                name: name.to_owned(),
+                spec: Box::new(spec),
+            });
+        }
+        if let Some(path) = spec.strip_prefix("file:") {
+            return Ok(DependencySpec::File(PathBuf::from(path)));
+        }
+        if ["./", "../", "/", "~/"].iter().any(|p| spec.starts_with(p)) {
+            return Ok(DependencySpec::File(PathBuf::from(spec)));
+        }
+        if GIT_PREFIXES.iter().any(|p| spec.starts_with(p)) {
+            return Ok(DependencySpec::Git(spec.to_owned()));
+        }
+        if spec.starts_with("http://") || spec.starts_with("https://") {
+            return Ok(DependencySpec::Url(spec.to_owned()));
+        }
+        match VersionRange::parse(spec) {
+            Ok(req) => Ok(DependencySpec::Range(req)),
+            Err(_) if is_tag(spec) => Ok(DependencySpec::Tag(spec.to_owned())),
+            Err(_) if is_github_shorthand(spec) => Ok(DependencySpec::Git(spec.to_owned())),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns the name of the package within the registry and the range of its versions this
+    /// specifier refers to, if any. `dependency_name` is the name the dependency is declared under,
+    /// which is the package name unless the dependency is an alias.
+    ///
+    /// Tags can't be resolved without the registry, and all other forms refer to packages outside
+    /// of it, which is why `None` is returned for them.
+    pub fn registry_range<'a>(&'a self, dependency_name: &'a str) -> Option<(&'a str, &'a VersionRange)> {
+        match *self {
+            DependencySpec::Range(ref req) => Some((dependency_name, req)),
+            DependencySpec::Alias { ref name, ref spec } => spec.registry_range(name),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DependencySpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DependencySpec::Range(ref req) => write!(f, "{}", req),
+            DependencySpec::Tag(ref tag) => write!(f, "{}", tag),
+            DependencySpec::Alias { ref name, ref spec } => write!(f, "npm:{}@{}", name, spec),
+            DependencySpec::Git(ref spec) |
+            DependencySpec::Url(ref spec) => write!(f, "{}", spec),
+            DependencySpec::File(ref path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
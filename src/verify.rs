@@ -0,0 +1,272 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use semver::Version;
+
+use dedup::{Error, InstructionOwned, PackageInfo, fetch_string, read_package_json};
+use hash::{content_hash, read_stored_hash, write_stored_hash};
+use paths::{absolute, resolve_link};
+use walk::{RepoPackage, find_repo_packages};
+
+/// A problem found within the repository by `verify_repo(...)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// The package.json of the package in `directory` could not be read or understood.
+    InvalidPackageJson { directory: PathBuf, reason: String },
+    /// The package in `directory` has a different name than its location suggests.
+    NameMismatch {
+        directory: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    /// The package in `directory` has a different version than its location suggests, which
+    /// happens if npm updated it in place.
+    VersionMismatch {
+        directory: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    /// The symbolic link at `link` points to `target`, which does not exist.
+    DanglingSymlink { link: PathBuf, target: PathBuf },
+    /// The version directory `directory` does not contain anything.
+    EmptyVersionDirectory { directory: PathBuf },
+    /// The contents of the package in `directory` changed since its hash was recorded.
+    ModifiedSinceIngestion {
+        directory: PathBuf,
+        recorded_hash: String,
+        actual_hash: String,
+    },
+}
+
+/// A `Finding` along with the instruction which would repair it, if there is one.
+/// Packages which were modified since their ingestion have no repair instruction, as the original
+/// contents cannot be restored from the repository alone. Neither have packages whose name or
+/// version differ from their location, as moving them would leave the symbolic links of the
+/// projects using them dangling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    pub finding: Finding,
+    pub repair: Option<InstructionOwned>,
+}
+
+/// Returns all directories containing a package for the given `<name>/<version>` directory, which
+/// is either the directory itself, or all of its `<platform>` sub-directories.
+fn package_directories(package: &RepoPackage) -> Vec<PathBuf> {
+    if package.directory.join("package.json").is_file() {
+        return vec![package.directory.clone()];
+    }
+    let mut platforms: Vec<_> = fs::read_dir(&package.directory)
+        .map(|entries| entries.filter_map(Result::ok).map(|e| e.path()).collect())
+        .unwrap_or_default();
+    platforms.sort();
+    platforms.into_iter()
+        .filter(|p| p.join("package.json").is_file())
+        .collect()
+}
+
+fn check_package_json(repo: &Path, package: &RepoPackage, directory: &Path, issues: &mut Vec<Issue>) {
+    let info = PackageInfo {
+        directory: directory.to_owned(),
+        root_directory: repo.to_owned(),
+    };
+    let invalid = |reason: String| {
+        Issue {
+            finding: Finding::InvalidPackageJson {
+                directory: directory.to_owned(),
+                reason,
+            },
+            repair: Some(InstructionOwned::RemoveFromRepo { this_directory: directory.to_owned() }),
+        }
+    };
+    let (name, version) = match read_package_json(&info).and_then(|pj| {
+        fetch_string(&pj, &info, "name").and_then(|n| fetch_string(&pj, &info, "version").map(|v| (n, v)))
+    }) {
+        Ok(nv) => nv,
+        Err(err) => return issues.push(invalid(err.to_string())),
+    };
+    let version = match Version::parse(&version) {
+        Ok(version) => version,
+        Err(err) => return issues.push(invalid(err.to_string())),
+    };
+
+    if name != package.name {
+        issues.push(Issue {
+            finding: Finding::NameMismatch {
+                directory: directory.to_owned(),
+                expected: package.name.clone(),
+                actual: name.clone(),
+            },
+            repair: None,
+        });
+    } else if format!("{}", version) != package.version {
+        issues.push(Issue {
+            finding: Finding::VersionMismatch {
+                directory: directory.to_owned(),
+                expected: package.version.clone(),
+                actual: format!("{}", version),
+            },
+            repair: None,
+        });
+    }
+}
+
+fn find_dangling_symlinks(dir: &Path, issues: &mut Vec<Issue>, errors: &mut Vec<Error>) {
+    let mut entries: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(Result::ok).map(|e| e.path()).collect(),
+        Err(err) => return errors.push(Error::ReadDirectory(dir.to_owned(), err)),
+    };
+    entries.sort();
+    for entry in entries {
+        let metadata = match entry.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                errors.push(Error::ReadDirectory(entry, err));
+                continue;
+            }
+        };
+        if metadata.file_type().is_symlink() {
+            match resolve_link(&entry) {
+                Ok(ref target) if target.exists() => {}
+                Ok(target) => {
+                    issues.push(Issue {
+                        finding: Finding::DanglingSymlink {
+                            link: entry.clone(),
+                            target,
+                        },
+                        repair: Some(InstructionOwned::RemoveSymlink { this_symlink: entry }),
+                    })
+                }
+                Err(err) => errors.push(Error::ReadDirectory(entry, err)),
+            }
+        } else if metadata.is_dir() {
+            find_dangling_symlinks(&entry, issues, errors);
+        }
+    }
+}
+
+/// Check all packages within `repo` for consistency, and return all issues found along with an
+/// instruction to repair them.
+///
+/// * the name and version in each `<name>/<version>/package.json` must match its location
+/// * symbolic links within packages must not dangle
+/// * version directories must not be empty
+/// * packages must not have changed since `record_content_hashes(...)` was called for them
+pub fn verify_repo<R>(repo: R) -> Result<Vec<Issue>, Vec<Error>>
+    where R: AsRef<Path>
+{
+    let repo = absolute(repo.as_ref());
+    let mut errors = Vec::new();
+    let mut issues = Vec::new();
+
+    for package in find_repo_packages(&repo, &mut errors) {
+        let is_empty = fs::read_dir(&package.directory).map(|mut e| e.next().is_none()).unwrap_or(false);
+        if is_empty {
+            issues.push(Issue {
+                finding: Finding::EmptyVersionDirectory { directory: package.directory.clone() },
+                repair: Some(InstructionOwned::RemoveFromRepo { this_directory: package.directory.clone() }),
+            });
+            continue;
+        }
+
+        let directories = package_directories(&package);
+        if directories.is_empty() {
+            issues.push(Issue {
+                finding: Finding::InvalidPackageJson {
+                    directory: package.directory.clone(),
+                    reason: String::from("package.json is missing"),
+                },
+                repair: Some(InstructionOwned::RemoveFromRepo { this_directory: package.directory.clone() }),
+            });
+        }
+        for directory in directories {
+            check_package_json(&repo, &package, &directory, &mut issues);
+            find_dangling_symlinks(&directory, &mut issues, &mut errors);
+            match read_stored_hash(&repo, &directory) {
+                Ok(Some(recorded_hash)) => {
+                    match content_hash(&directory) {
+                        Ok(ref actual_hash) if *actual_hash == recorded_hash => {}
+                        Ok(actual_hash) => {
+                            issues.push(Issue {
+                                finding: Finding::ModifiedSinceIngestion {
+                                    directory,
+                                    recorded_hash,
+                                    actual_hash,
+                                },
+                                repair: None,
+                            })
+                        }
+                        Err(err) => errors.push(Error::ContentHash(directory, err)),
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => errors.push(Error::ContentHash(directory, err)),
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(issues)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Record the content hash of all packages in `repo` which don't have one yet, to allow
+/// `verify_repo(...)` to detect later modifications. This should be called right after packages
+/// were moved into the repository.
+/// Returns the amount of newly recorded hashes.
+pub fn record_content_hashes<R>(repo: R) -> Result<usize, Vec<Error>>
+    where R: AsRef<Path>
+{
+    let repo = absolute(repo.as_ref());
+    let mut errors = Vec::new();
+    let directories: Vec<_> = find_repo_packages(&repo, &mut errors).iter().flat_map(package_directories).collect();
+    let recorded = record_hashes(&repo, &directories, &mut errors);
+
+    if errors.is_empty() {
+        Ok(recorded)
+    } else {
+        Err(errors)
+    }
+}
+
+/// As `record_content_hashes(...)`, but only for the package `directories`, which are usually the
+/// ones just moved into `repo`. Directories outside of `repo` are ignored.
+pub fn record_content_hashes_of<R, P>(repo: R, directories: &[P]) -> Result<usize, Vec<Error>>
+    where R: AsRef<Path>,
+          P: AsRef<Path>
+{
+    let repo = absolute(repo.as_ref());
+    let directories: Vec<_> = directories.iter()
+        .map(|d| absolute(d.as_ref()))
+        .filter(|d| d.starts_with(&repo) && d.join("package.json").is_file())
+        .collect();
+    let mut errors = Vec::new();
+    let recorded = record_hashes(&repo, &directories, &mut errors);
+
+    if errors.is_empty() {
+        Ok(recorded)
+    } else {
+        Err(errors)
+    }
+}
+
+fn record_hashes(repo: &Path, directories: &[PathBuf], errors: &mut Vec<Error>) -> usize {
+    let mut recorded = 0;
+    for directory in directories {
+        let result = read_stored_hash(repo, directory).and_then(|h| match h {
+            Some(_) => Ok(false),
+            None => {
+                content_hash(directory)
+                    .and_then(|hash| write_stored_hash(repo, directory, &hash))
+                    .map(|_| true)
+            }
+        });
+        match result {
+            Ok(true) => recorded += 1,
+            Ok(false) => {}
+            Err(err) => errors.push(Error::ContentHash(directory.clone(), err)),
+        }
+    }
+    recorded
+}
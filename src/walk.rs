@@ -0,0 +1,291 @@
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::io;
+use std::vec;
+
+use dedup::{Error, PackageInfo};
+use parallel::parallel_map;
+use paths::{absolute, resolve_link};
+
+/// A lazy, depth-first iterator over all packages within a `node_modules` directory, as created
+/// by `find_packages(...)`.
+pub struct Packages {
+    root_directory: PathBuf,
+    stack: Vec<vec::IntoIter<PathBuf>>,
+    next_directory: Option<PathBuf>,
+    /// If false, the `node_modules` directories of packages are not entered.
+    descend: bool,
+}
+
+/// Return an iterator over all packages found in the `node_modules` directory of the project at
+/// `root`, including the ones in nested `node_modules` directories of packages.
+///
+/// Packages are yielded depth-first, with each package followed by the contents of its own
+/// `node_modules` directory. Entries are visited in lexical order, which makes the traversal
+/// deterministic. Symbolic links are never followed, and hidden directories as well as directories
+/// without a `package.json` are not considered packages. Scoped packages are found within their `@scope` directory.
+/// The `root_directory` of each yielded `PackageInfo` is `<root>/node_modules`.
+pub fn find_packages<P>(root: P) -> Packages
+    where P: AsRef<Path>
+{
+    let node_modules = root.as_ref().join("node_modules");
+    Packages {
+        root_directory: node_modules.clone(),
+        stack: Vec::new(),
+        next_directory: Some(node_modules),
+        descend: true,
+    }
+}
+
+/// As `find_packages(...)`, but the package trees below each top-level package are scanned on up
+/// to `threads` threads. The result is identical to collecting the iterator returned by
+/// `find_packages(...)`, which makes this a drop-in replacement for huge `node_modules`
+/// directories on slow file systems.
+pub fn find_packages_parallel<P>(root: P, threads: usize) -> Vec<Result<PackageInfo, Error>>
+    where P: AsRef<Path>
+{
+    let node_modules = root.as_ref().join("node_modules");
+    let top_level: Vec<_> = Packages { descend: false, ..find_packages(root) }.collect();
+    let directories: Vec<_> = top_level.iter().filter_map(|p| p.as_ref().ok()).map(|p| p.directory.clone()).collect();
+    let mut subtrees = parallel_map(&directories, threads, |dir| {
+            let mut packages = Packages {
+                root_directory: node_modules.clone(),
+                stack: Vec::new(),
+                next_directory: Some(dir.join("node_modules")),
+                descend: true,
+            };
+            let mut found = Vec::new();
+            while let Some(p) = packages.next_entry() {
+                found.push(p);
+            }
+            found
+        })
+        .into_iter();
+
+    let mut out = Vec::new();
+    for p in top_level {
+        let is_package = p.is_ok();
+        out.push(p);
+        if is_package {
+            let subtree = subtrees.next().expect("a subtree per top-level package");
+            out.extend(subtree.into_iter().map(|p| p.map_err(|(dir, err)| Error::ReadDirectory(dir, err))));
+        }
+    }
+    out
+}
+
+fn sorted_entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        entries.push(entry?.path());
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// An error of the walk, which unlike `Error` can be sent between threads.
+type WalkError = (PathBuf, io::Error);
+
+impl Packages {
+    fn enter(&mut self, dir: PathBuf) -> Result<(), WalkError> {
+        match sorted_entries(&dir) {
+            Ok(entries) => {
+                self.stack.push(entries.into_iter());
+                Ok(())
+            }
+            // Only the top-level `node_modules` directory is mandatory
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound && dir != self.root_directory => Ok(()),
+            Err(err) => Err((dir, err)),
+        }
+    }
+
+    fn next_entry(&mut self) -> Option<Result<PackageInfo, WalkError>> {
+        loop {
+            if let Some(dir) = self.next_directory.take() {
+                if let Err(err) = self.enter(dir) {
+                    return Some(Err(err));
+                }
+            }
+
+            let path = match self.stack.last_mut() {
+                None => return None,
+                Some(entries) => entries.next(),
+            };
+            let path = match path {
+                Some(path) => path,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            let metadata = match path.symlink_metadata() {
+                Ok(metadata) => metadata,
+                Err(err) => return Some(Err((path, err))),
+            };
+            // Hidden entries are ignored by npm, and used for temporary files while applying changes
+            let is_hidden = path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with('.'));
+            if !metadata.is_dir() || is_hidden {
+                continue;
+            }
+
+            if !path.join("package.json").is_file() {
+                // Scoped packages live one level deeper, like `node_modules/@scope/name`
+                let is_scope = path.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with('@'));
+                if is_scope {
+                    match sorted_entries(&path) {
+                        Ok(entries) => self.stack.push(entries.into_iter()),
+                        Err(err) => return Some(Err((path, err))),
+                    }
+                }
+                continue;
+            }
+            if self.descend {
+                self.next_directory = Some(path.join("node_modules"));
+            }
+            return Some(Ok(PackageInfo {
+                directory: path,
+                root_directory: self.root_directory.clone(),
+            }));
+        }
+    }
+}
+
+impl Iterator for Packages {
+    type Item = Result<PackageInfo, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().map(|p| p.map_err(|(dir, err)| Error::ReadDirectory(dir, err)))
+    }
+}
+
+/// A symbolic link within a project's `node_modules` hierarchy pointing into the repository.
+pub(crate) struct RepoLink {
+    /// The location of the link as seen from the project, which may be below other links.
+    pub link: PathBuf,
+    /// The absolute location within the repository the link points to.
+    pub target: PathBuf,
+}
+
+/// Find all symbolic links within `node_modules` which point into `repo`, depth-first and in
+/// lexical order. Links are followed into the repository to find links in the `node_modules`
+/// directories of linked packages, which are reported relative to the outer link.
+/// I/O errors are collected in `errors`.
+pub(crate) fn find_repo_links(node_modules: &Path, repo: &Path, errors: &mut Vec<Error>) -> Vec<RepoLink> {
+    fn recurse(logical: &Path,
+               physical: &Path,
+               repo: &Path,
+               followed: &mut Vec<PathBuf>,
+               out: &mut Vec<RepoLink>,
+               errors: &mut Vec<Error>) {
+        let entries = match sorted_entries(physical) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return,
+            Err(err) => return errors.push(Error::ReadDirectory(physical.to_owned(), err)),
+        };
+        for entry in entries {
+            let name = entry.file_name().expect("entries to have a name");
+            let metadata = match entry.symlink_metadata() {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    errors.push(Error::ReadDirectory(entry, err));
+                    continue;
+                }
+            };
+            if metadata.file_type().is_symlink() {
+                let target = match resolve_link(&entry) {
+                    Ok(target) => absolute(&target),
+                    Err(err) => {
+                        errors.push(Error::ReadDirectory(entry, err));
+                        continue;
+                    }
+                };
+                // Guard against cycles between packages within the repository
+                if !target.starts_with(repo) || !target.is_dir() || followed.contains(&target) {
+                    continue;
+                }
+                out.push(RepoLink {
+                    link: logical.join(name),
+                    target: target.clone(),
+                });
+                followed.push(target.clone());
+                recurse(&logical.join(name).join("node_modules"),
+                        &target.join("node_modules"),
+                        repo,
+                        followed,
+                        out,
+                        errors);
+                followed.pop();
+            } else if metadata.is_dir() {
+                let (logical, physical) = if name.to_str().is_some_and(|n| n.starts_with('@')) {
+                    (logical.join(name), entry.clone())
+                } else {
+                    (logical.join(name).join("node_modules"), entry.join("node_modules"))
+                };
+                recurse(&logical, &physical, repo, followed, out, errors);
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    recurse(node_modules, node_modules, &absolute(repo), &mut Vec::new(), &mut out, errors);
+    out
+}
+
+/// A `<name>/<version>` directory within the repository.
+pub(crate) struct RepoPackage {
+    /// The package name, including the scope for scoped packages.
+    pub name: String,
+    /// The version as used in the directory name.
+    pub version: String,
+    pub directory: PathBuf,
+}
+
+/// Find all `<name>/<version>` directories in `repo`, including `@scope/<name>/<version>` ones,
+/// in lexical order. Entries starting with a dot are reserved for bookkeeping and are skipped.
+pub(crate) fn find_repo_packages(repo: &Path, errors: &mut Vec<Error>) -> Vec<RepoPackage> {
+    fn directories(dir: &Path, errors: &mut Vec<Error>) -> Vec<(String, PathBuf)> {
+        match sorted_entries(dir) {
+            Ok(entries) => {
+                entries.into_iter()
+                    .filter(|p| p.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false))
+                    .filter_map(|p| {
+                        p.file_name().and_then(|n| n.to_str()).map(str::to_owned).map(|n| (n, p.clone()))
+                    })
+                    .filter(|(n, _)| !n.starts_with('.'))
+                    .collect()
+            }
+            Err(err) => {
+                errors.push(Error::ReadDirectory(dir.to_owned(), err));
+                Vec::new()
+            }
+        }
+    }
+
+    let mut names = Vec::new();
+    for (name, dir) in directories(repo, errors) {
+        if name.starts_with('@') {
+            for (scoped_name, scoped_dir) in directories(&dir, errors) {
+                names.push((format!("{}/{}", name, scoped_name), scoped_dir));
+            }
+        } else {
+            names.push((name, dir));
+        }
+    }
+
+    let mut out = Vec::new();
+    for (name, dir) in names {
+        for (version, directory) in directories(&dir, errors) {
+            out.push(RepoPackage {
+                name: name.clone(),
+                version,
+                directory,
+            });
+        }
+    }
+    out
+}
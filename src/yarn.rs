@@ -0,0 +1,308 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use dedup::{Error, PackageInfo, is_valid_package_name, read_package_json};
+use lock::LockedPackage;
+use range::RangeParseError;
+use spec::DependencySpec;
+
+/// A package name along with the range of versions requested for it, like `lodash@^4.17.0`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YarnDescriptor {
+    pub name: String,
+    /// The range as it would appear in package.json. The `npm:` protocol yarn berry prefixes
+    /// registry ranges with is removed, but kept for aliases like `npm:other@^1.0.0`.
+    pub range: String,
+}
+
+impl YarnDescriptor {
+    /// Parse `descriptor`, which is `<name>@<range>` with an optional scope before the name.
+    fn parse(descriptor: &str) -> Option<YarnDescriptor> {
+        let at = descriptor.get(1..)?.find('@')? + 1;
+        Some(YarnDescriptor::new(&descriptor[..at], &descriptor[at + 1..]))
+    }
+
+    fn new(name: &str, range: &str) -> YarnDescriptor {
+        let range = match range.strip_prefix("npm:") {
+            Some(aliased) if aliased.get(1..).is_some_and(|a| a.contains('@')) => range,
+            Some(registry_range) => registry_range,
+            None => range,
+        };
+        YarnDescriptor {
+            name: name.to_owned(),
+            range: range.to_owned(),
+        }
+    }
+
+    /// Classify the range like the specifier of a dependency within package.json.
+    pub fn spec(&self) -> Result<DependencySpec, RangeParseError> {
+        DependencySpec::parse(&self.range)
+    }
+}
+
+/// A package version as yarn resolved it, along with all descriptors resolved to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YarnLockEntry {
+    /// The descriptors of the dependencies which resolved to this entry, in the order yarn
+    /// listed them.
+    pub descriptors: Vec<YarnDescriptor>,
+    /// The name of the package within the registry, which differs from the name within its
+    /// descriptors if the package was installed under an alias.
+    pub name: String,
+    pub version: String,
+    /// The `resolved` URL of yarn v1, or the `resolution` locator of yarn berry, like
+    /// `lodash@npm:4.17.21`.
+    pub resolved: Option<String>,
+    /// The `integrity` string of yarn v1, or the `checksum` of yarn berry.
+    pub integrity: Option<String>,
+    /// The dependencies of the package, which can be looked up with `YarnLock::resolve(...)`.
+    pub dependencies: Vec<YarnDescriptor>,
+    pub optional_dependencies: Vec<YarnDescriptor>,
+}
+
+/// The contents of a `yarn.lock` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YarnLock {
+    /// True if the file was written by yarn 2 or later, which use a subset of YAML.
+    pub berry: bool,
+    /// All entries in the order of the file.
+    pub entries: Vec<YarnLockEntry>,
+}
+
+impl YarnLock {
+    /// Returns the entry the dependency `name` with the version specifier `range` from package.json
+    /// resolved to, if any.
+    pub fn resolve(&self, name: &str, range: &str) -> Option<&YarnLockEntry> {
+        let wanted = YarnDescriptor::new(name, range);
+        self.entries.iter().find(|e| e.descriptors.contains(&wanted))
+    }
+}
+
+fn unquote(s: &str) -> &str {
+    let s = s.trim();
+    s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap_or(s)
+}
+
+/// Split a field into key and value, which are separated by a space in yarn v1 and by a colon
+/// in yarn berry. Keys are quoted if they contain special characters, like scoped package names.
+fn key_value(line: &str, berry: bool) -> Option<(&str, &str)> {
+    let (key, rest) = match line.strip_prefix('"') {
+        Some(quoted) => {
+            let end = quoted.find('"')?;
+            (&quoted[..end], &quoted[end + 1..])
+        }
+        None => {
+            let end = line.find(if berry { ':' } else { ' ' }).unwrap_or(line.len());
+            (&line[..end], &line[end..])
+        }
+    };
+    let rest = if berry { rest.strip_prefix(':')? } else { rest };
+    Some((key, unquote(rest)))
+}
+
+/// The package name of the yarn berry locator `locator`, like `lodash@npm:4.17.21`.
+fn locator_name(locator: &str) -> Option<&str> {
+    locator.get(1..)?.find('@').map(|at| &locator[..at + 1])
+}
+
+fn parse(lockfile: &Path, contents: &str) -> Result<YarnLock, Error> {
+    let error = |line: usize, expectation: &str| {
+        Error::Lockfile(lockfile.to_owned(), format!("line {}: {}", line + 1, expectation))
+    };
+    let berry = contents.lines().any(|l| l.starts_with("__metadata:"));
+    let mut entries: Vec<YarnLockEntry> = Vec::new();
+    // The entry fields are being added to, and the section nested fields belong to
+    let mut entry: Option<&mut YarnLockEntry> = None;
+    let mut section = String::new();
+
+    for (n, line) in contents.lines().enumerate() {
+        let content = line.trim_start_matches(' ');
+        if content.is_empty() || content.starts_with('#') {
+            continue;
+        }
+        let content = content.trim_end();
+        match line.len() - line.trim_start_matches(' ').len() {
+            0 => {
+                let key = content.strip_suffix(':').ok_or_else(|| error(n, "expected an entry"))?;
+                if berry && key == "__metadata" {
+                    entry = None;
+                    continue;
+                }
+                // yarn v1 quotes each descriptor, while yarn berry quotes the whole list
+                let descriptors = key.split(',')
+                    .map(|d| YarnDescriptor::parse(d.trim().trim_matches('"')))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| error(n, "expected descriptors like <name>@<range>"))?;
+                entries.push(YarnLockEntry {
+                    descriptors,
+                    name: String::new(),
+                    version: String::new(),
+                    resolved: None,
+                    integrity: None,
+                    dependencies: Vec::new(),
+                    optional_dependencies: Vec::new(),
+                });
+                entry = entries.last_mut();
+            }
+            2 => {
+                let e = match entry {
+                    Some(ref mut e) => e,
+                    None => continue,
+                };
+                if let Some(name) = content.strip_suffix(':') {
+                    section = name.to_owned();
+                    continue;
+                }
+                section.clear();
+                let (key, value) = key_value(content, berry).ok_or_else(|| error(n, "expected a field"))?;
+                match key {
+                    "version" => e.version = value.to_owned(),
+                    "resolved" | "resolution" => e.resolved = Some(value.to_owned()),
+                    "integrity" | "checksum" => e.integrity = Some(value.to_owned()),
+                    _ => {}
+                }
+            }
+            4 => {
+                let e = match entry {
+                    Some(ref mut e) => e,
+                    None => continue,
+                };
+                let dependencies = match section.as_str() {
+                    "dependencies" => &mut e.dependencies,
+                    "optionalDependencies" => &mut e.optional_dependencies,
+                    _ => continue,
+                };
+                let (name, range) = key_value(content, berry).ok_or_else(|| error(n, "expected a dependency"))?;
+                dependencies.push(YarnDescriptor::new(name, range));
+            }
+            // Only the metadata of dependencies is nested any deeper
+            _ => {}
+        }
+    }
+
+    for (n, e) in entries.iter_mut().enumerate() {
+        if e.version.is_empty() {
+            return Err(Error::Lockfile(lockfile.to_owned(), format!("entry {} has no version", n + 1)));
+        }
+        let resolution = e.resolved.as_deref().filter(|_| berry).and_then(locator_name);
+        e.name = match resolution {
+            Some(name) => name.to_owned(),
+            None => {
+                let first = &e.descriptors[0];
+                match first.spec() {
+                    Ok(DependencySpec::Alias { name, .. }) => name,
+                    _ => first.name.clone(),
+                }
+            }
+        };
+    }
+    Ok(YarnLock { berry, entries })
+}
+
+/// Read the `yarn.lock` of the project at `root`, as written by yarn v1 or yarn berry.
+///
+/// This provides the same requirement data for projects managed by yarn that deduplication reads
+/// from each package.json, as each dependency of an entry resolves to another entry via
+/// `YarnLock::resolve(...)`.
+pub fn read_yarn_lock<P>(root: P) -> Result<YarnLock, Error>
+    where P: AsRef<Path>
+{
+    let lockfile = root.as_ref().join("yarn.lock");
+    let mut contents = String::new();
+    fs::File::open(&lockfile)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|err| Error::Lockfile(lockfile.clone(), err.to_string()))?;
+    parse(&lockfile, &contents)
+}
+
+/// The protocols of dependencies yarn links to a local directory rather than installing them.
+const LOCAL_PROTOCOLS: &[&str] = &["file:", "link:", "portal:", "workspace:"];
+
+/// Returns the directory the dependency `name` of the package at `dependent` is installed in, if
+/// any. Like Node, the `node_modules` directories of `dependent` and all of its ancestors within
+/// the project at `root` are searched, as yarn hoists packages as far up as possible.
+fn installed_directory(root: &Path, dependent: &Path, name: &str) -> Option<PathBuf> {
+    dependent.ancestors()
+        .take_while(|dir| dir.starts_with(root))
+        .filter(|dir| dir.file_name().map(|n| n != "node_modules").unwrap_or(true))
+        .map(|dir| name.split('/').fold(dir.join("node_modules"), |p, c| p.join(c)))
+        .find(|dir| dir.join("package.json").is_file())
+}
+
+/// Read the packages which the `yarn.lock` of the project at `root` resolves its dependencies to,
+/// as written by yarn v1 or by yarn berry with the `node-modules` linker.
+///
+/// As `yarn.lock` doesn't record where a package is installed, each one is located like Node
+/// would locate it, starting from the `package.json` of the project and following the
+/// dependencies recorded within the lockfile. Dependencies which are not installed, like optional
+/// ones for other platforms, are left out, as are the ones linked from local directories.
+/// Symbolic links are returned, but not followed.
+///
+/// Just like `find_locked_packages(...)`, packages are returned in the order of their directory,
+/// and the `root_directory` of each `PackageInfo` is `<root>/node_modules`. Dependencies which
+/// are missing from the lockfile or have no valid package name fail with `Error::Lockfile`.
+pub fn find_yarn_locked_packages<P>(root: P) -> Result<Vec<LockedPackage>, Error>
+    where P: AsRef<Path>
+{
+    let root = root.as_ref();
+    let lockfile = root.join("yarn.lock");
+    let lock = read_yarn_lock(root)?;
+    let project = PackageInfo {
+        directory: root.to_owned(),
+        root_directory: root.join("node_modules"),
+    };
+    let manifest = read_package_json(&project)?;
+    let mut pending = Vec::new();
+    for key in &["dependencies", "devDependencies", "optionalDependencies"] {
+        if let Some(deps) = manifest.get(*key).and_then(Value::as_object) {
+            pending.extend(deps.iter()
+                .filter_map(|(name, range)| range.as_str().map(|r| (root.to_owned(), YarnDescriptor::new(name, r)))));
+        }
+    }
+
+    let mut visited = BTreeSet::new();
+    let mut packages = Vec::new();
+    while let Some((dependent, descriptor)) = pending.pop() {
+        if !is_valid_package_name(&descriptor.name) {
+            return Err(Error::Lockfile(lockfile, format!("'{}' is no valid package name", descriptor.name)));
+        }
+        if LOCAL_PROTOCOLS.iter().any(|p| descriptor.range.starts_with(p)) ||
+           matches!(descriptor.spec(), Ok(DependencySpec::File(_))) {
+            continue;
+        }
+        let entry = lock.entries.iter().find(|e| e.descriptors.contains(&descriptor)).ok_or_else(|| {
+                Error::Lockfile(lockfile.clone(),
+                                format!("dependency '{}@{}' is missing", descriptor.name, descriptor.range))
+            })?;
+        let directory = match installed_directory(root, &dependent, &descriptor.name) {
+            Some(directory) => directory,
+            None => continue,
+        };
+        if !visited.insert(directory.clone()) {
+            continue;
+        }
+        let is_link = fs::symlink_metadata(&directory).map(|m| m.file_type().is_symlink()).unwrap_or(false);
+        if !is_link {
+            pending.extend(entry.dependencies
+                .iter()
+                .chain(&entry.optional_dependencies)
+                .map(|d| (directory.clone(), d.clone())));
+        }
+        packages.push(LockedPackage {
+            name: entry.name.clone(),
+            version: entry.version.clone(),
+            resolved: entry.resolved.clone(),
+            integrity: entry.integrity.clone(),
+            info: PackageInfo {
+                directory,
+                root_directory: root.join("node_modules"),
+            },
+        });
+    }
+    packages.sort_by(|a, b| a.info.directory.cmp(&b.info.directory));
+    Ok(packages)
+}
@@ -0,0 +1,134 @@
+#![allow(deprecated)]
+
+extern crate hamcrest;
+extern crate tempdir;
+extern crate npm_tools;
+
+mod utils;
+
+use std::process::Command;
+use hamcrest::*;
+use tempdir::TempDir;
+use std::fs::File;
+use std::io::Write;
+
+fn npm_dedup() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_npm-dedup"))
+}
+
+#[test]
+fn plan_prints_instructions_as_json_without_changing_anything() {
+    let repo = TempDir::new("npm_repo_path").unwrap();
+    let project = TempDir::new("project").unwrap();
+    let dir = project.path().join("node_modules").join("a");
+    utils::make_package(&dir, "a", "1.0.0");
+
+    let out = npm_dedup()
+        .args(["plan", "--json", "--repo"])
+        .arg(repo.path())
+        .arg(project.path())
+        .output()
+        .unwrap();
+    assert_that(out.status.success(), equal_to(true));
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_that(stdout.lines().count(), equal_to(1));
+    assert_that(stdout.contains(r#""type":"MoveAndSymlink""#), equal_to(true));
+    assert_that(dir.join("package.json").is_file(), equal_to(true));
+}
+
+#[test]
+fn it_exits_with_non_zero_status_on_error() {
+    let repo = TempDir::new("npm_repo_path").unwrap();
+    let project = TempDir::new("project").unwrap();
+
+    let out = npm_dedup().arg("dedup").arg(project.path()).arg("--repo").arg(repo.path()).output().unwrap();
+    assert_that(out.status.success(), equal_to(false));
+    assert_that(String::from_utf8(out.stderr).unwrap().starts_with("error: "), equal_to(true));
+}
+
+#[test]
+fn check_lock_fails_if_node_modules_differs_from_the_lockfile() {
+    let project = TempDir::new("project").unwrap();
+    let dir = project.path().join("node_modules").join("a");
+    utils::make_package(&dir, "a", "1.0.0");
+    File::create(project.path().join("package-lock.json"))
+        .unwrap()
+        .write_all(br#"{"lockfileVersion": 3, "packages": {"node_modules/b": {"version": "1.0.0"}}}"#)
+        .unwrap();
+
+    let out = npm_dedup().args(["check-lock", "--json"]).arg(project.path()).output().unwrap();
+    assert_that(out.status.success(), equal_to(false));
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_that(stdout.lines().count(), equal_to(2));
+    assert_that(stdout.contains(r#""discrepancy":"NotInLock""#), equal_to(true));
+    assert_that(stdout.contains(r#""discrepancy":"NotOnDisk""#), equal_to(true));
+}
+
+#[test]
+fn dedup_takes_the_packages_from_yarn_lock() {
+    let repo = TempDir::new("npm_repo_path").unwrap();
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    for name in &["a", "stray"] {
+        utils::make_package(&node_modules.join(name), name, "1.0.0");
+    }
+    utils::write_package_json(project.path(), r#"{"dependencies": {"a": "^1.0.0"}}"#);
+    utils::write_file(&project.path().join("yarn.lock"), "a@^1.0.0:\n  version \"1.0.0\"\n");
+
+    let out = npm_dedup()
+        .args(["dedup", "--yarn-lock", "--repo"])
+        .arg(repo.path())
+        .arg(project.path())
+        .output()
+        .unwrap();
+    assert_that(out.status.success(), equal_to(true));
+    assert_that(node_modules.join("a").symlink_metadata().unwrap().file_type().is_symlink(), equal_to(true));
+    assert_that(repo.path().join("a").join("1.0.0").join("package.json").is_file(), equal_to(true));
+    assert_that(node_modules.join("stray").symlink_metadata().unwrap().is_dir(), equal_to(true));
+    assert_that(repo.path().join("stray").exists(), equal_to(false));
+}
+
+#[test]
+fn revert_unregisters_the_project() {
+    let repo = TempDir::new("npm_repo_path").unwrap();
+    let project = TempDir::new("project").unwrap();
+    let dir = project.path().join("node_modules").join("a");
+    utils::make_package(&dir, "a", "1.0.0");
+    let registry = repo.path().join(".npm-tools").join("projects");
+
+    for command in &["dedup", "revert"] {
+        let out = npm_dedup().args([command, "--repo"]).arg(repo.path()).arg(project.path()).output().unwrap();
+        assert_that(out.status.success(), equal_to(true));
+    }
+    assert_that(std::fs::read_to_string(registry).unwrap(), equal_to(String::new()));
+    assert_that(dir.join("package.json").is_file(), equal_to(true));
+    assert_that(dir.symlink_metadata().unwrap().is_dir(), equal_to(true));
+}
+
+#[test]
+fn dedup_only_records_the_hashes_of_packages_it_moved_into_the_repo() {
+    let repo = TempDir::new("npm_repo_path").unwrap();
+    let (symlinked, hardlinked) = (TempDir::new("project").unwrap(), TempDir::new("project").unwrap());
+    let packages = [(symlinked.path().join("node_modules").join("a"), "a"),
+                    (hardlinked.path().join("node_modules").join("c"), "c"),
+                    (repo.path().join("b").join("1.0.0"), "b")];
+    for &(ref dir, name) in &packages {
+        utils::make_package(dir, name, "1.0.0");
+    }
+
+    for &(project, mode) in &[(&symlinked, None), (&hardlinked, Some("--hardlink"))] {
+        let out = npm_dedup()
+            .arg("dedup")
+            .args(mode)
+            .arg("--repo")
+            .arg(repo.path())
+            .arg(project.path())
+            .output()
+            .unwrap();
+        assert_that(out.status.success(), equal_to(true));
+    }
+    let hashes = repo.path().join(".npm-tools").join("hashes");
+    assert_that(hashes.join("a").join("1.0.0").is_file(), equal_to(true));
+    assert_that(hashes.join("c").join("1.0.0").is_file(), equal_to(true));
+    assert_that(hashes.join("b").exists(), equal_to(false));
+}
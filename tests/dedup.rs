@@ -1,3 +1,5 @@
+#![allow(deprecated)]
+
 extern crate hamcrest;
 extern crate tempdir;
 extern crate npm_tools;
@@ -8,7 +10,9 @@ extern crate quick_error;
 mod utils;
 
 use std::path::PathBuf;
-use npm_tools::{deduplicate_into, Visitor, PackageInfo, InstructionOwned, Instruction, Error};
+use std::sync::Arc;
+use npm_tools::{deduplicate_into, deduplicate_into_with, find_packages, Visitor, PackageInfo, InstructionOwned,
+                Instruction, Error, DeduplicationOptions, SymlinkStyle, PlatformSegment, PnpmLayout};
 use hamcrest::*;
 use tempdir::TempDir;
 use std::fs::{File, create_dir_all};
@@ -18,6 +22,7 @@ use std::os::unix::fs::symlink;
 #[derive(Default)]
 struct Collector {
     preprocessed_packages: Vec<PackageInfo>,
+    warnings: Vec<PackageInfo>,
     instructions: Vec<InstructionOwned>,
     fail_on_change: bool,
 }
@@ -36,6 +41,10 @@ impl Visitor for Collector {
         self.preprocessed_packages.push(package.clone());
     }
 
+    fn warning(&mut self, package: &PackageInfo, _: &npm_tools::Error) {
+        self.warnings.push(package.clone());
+    }
+
     fn change(&mut self, action: Instruction) -> Result<(), Self::Error> {
         if self.fail_on_change {
             Err(FakeError::Action(action.into()))
@@ -51,6 +60,7 @@ fn setup(root: &str) -> (TempDir, Collector, utils::PackageMaker) {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args, clippy::unit_arg)]
 fn it_does_not_tell_visitor_to_symlink_a_direct_dependency_to_repo_if_it_is_a_symlink_to_correct_destination_already
     () {
     let repo = utils::transient_repo_path();
@@ -80,6 +90,7 @@ fn it_does_not_tell_visitor_to_symlink_a_direct_dependency_to_repo_if_it_is_a_sy
 }
 
 #[test]
+#[allow(clippy::unit_arg)]
 fn it_tells_visitor_to_symlink_a_direct_dependency_to_repo_if_version_does_exist_there() {
     let (repo, mut cl, make) = setup("reveal.js-unnested");
     let abs_destination = repo.path().join("sigmund").join("1.0.1");
@@ -100,6 +111,7 @@ fn it_tells_visitor_to_symlink_a_direct_dependency_to_repo_if_version_does_exist
 }
 
 #[test]
+#[allow(clippy::unit_arg)]
 fn it_tells_visitor_to_move_and_symlink_a_direct_dependency_to_repo_if_version_does_not_exist() {
     let (repo, mut cl, make) = setup("reveal.js-unnested");
 
@@ -151,6 +163,7 @@ fn it_informs_the_visitor_right_after_something_went_wrong() {
 /// Even though duplicate packages can be returned, those should always be on different spots in
 /// the hierarchy (i.e. the actual directories are different from each other, like `a` and `c/a`).
 #[test]
+#[allow(clippy::assertions_on_constants)]
 fn it_rejects_duplicate_packages() {
     let (repo, mut cl, make) = setup("reveal.js-unnested");
 
@@ -163,3 +176,476 @@ fn it_rejects_duplicate_packages() {
         _ => assert!(false),
     }
 }
+
+#[test]
+fn it_keeps_versions_which_only_differ_in_their_build_metadata_apart() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let root = project.path().join("node_modules");
+    let ps: Vec<_> = [("a", "1.0.0+a"), ("b/node_modules/a", "1.0.0+b")]
+        .iter()
+        .map(|&(dir, version)| {
+            let dir = root.join(dir);
+            utils::make_package(&dir, "a", version);
+            PackageInfo {
+                directory: dir,
+                root_directory: root.clone(),
+            }
+        })
+        .collect();
+    let mut cl = Collector::default();
+    deduplicate_into(repo.path(), &ps, &mut cl).unwrap();
+
+    let destinations: Vec<_> = cl.instructions
+        .iter()
+        .map(|i| match *i {
+            InstructionOwned::MoveAndSymlink { ref to_here, .. } => to_here.clone(),
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_that(destinations,
+                equal_to(vec![repo.path().join("a").join("1.0.0+a"), repo.path().join("a").join("1.0.0+b")]));
+}
+
+#[test]
+fn it_places_scoped_packages_into_their_scope_directory_within_the_repo() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let root = project.path().join("node_modules");
+    let dir = root.join("@babel").join("core");
+    utils::write_package_json(&dir, r#"{"version":"7.0.0", "name":"@babel/core"}"#);
+
+    let ps = [PackageInfo {
+                  directory: dir,
+                  root_directory: root,
+              }];
+    let mut cl = Collector::default();
+    deduplicate_into(repo.path(), &ps, &mut cl).unwrap();
+
+    assert_that(&cl.instructions, of_len(1));
+    match cl.instructions[0] {
+        InstructionOwned::MoveAndSymlink { ref to_here, .. } => {
+            assert_that(to_here, equal_to(&repo.path().join("@babel").join("core").join("7.0.0")));
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn it_rejects_package_names_which_would_escape_the_repo() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let root = project.path().join("node_modules");
+
+    let ps: Vec<_> = ["../../evil", "/abs", "@scope", "@a/b/c", "."]
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let dir = root.join(format!("p{}", i));
+            utils::write_package_json(&dir, &format!(r#"{{"version":"1.0.0", "name":"{}"}}"#, name));
+            PackageInfo {
+                directory: dir,
+                root_directory: root.clone(),
+            }
+        })
+        .collect();
+    let mut cl = Collector::default();
+    let ve = deduplicate_into(repo.path(), &ps, &mut cl).err().unwrap();
+
+    assert_that(&ve, of_len(ps.len()));
+    assert_that(ve.iter().all(|e| matches!(*e, Error::InvalidPackageName(..))),
+                equal_to(true));
+    assert_that(&cl.instructions, of_len(0));
+}
+
+#[test]
+fn it_can_compute_symlink_destinations_relative_to_the_symlink() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let root = project.path().join("node_modules");
+    let dir = root.join("sigmund");
+    utils::write_package_json(&dir, r#"{"version":"1.0.1", "name":"sigmund"}"#);
+    let destination = repo.path().join("sigmund").join("1.0.1");
+    utils::write_package_json(&destination, r#"{"version":"1.0.1", "name":"sigmund"}"#);
+
+    let ps = [PackageInfo {
+                  directory: dir,
+                  root_directory: root.clone(),
+              }];
+    let options = DeduplicationOptions { symlink_style: SymlinkStyle::Relative, ..Default::default() };
+    let mut cl = Collector::default();
+    deduplicate_into_with(repo.path(), &ps, &options, &mut cl).unwrap();
+
+    assert_that(&cl.instructions, of_len(1));
+    match cl.instructions[0] {
+        InstructionOwned::ReplaceWithSymlink { ref symlink_destination, .. } => {
+            assert_that(symlink_destination.is_relative(), equal_to(true));
+            assert_that(root.join(symlink_destination).canonicalize().unwrap(),
+                        equal_to(destination.canonicalize().unwrap()));
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn move_destinations(options: &DeduplicationOptions, packages: &[&str]) -> (TempDir, Vec<PathBuf>) {
+    let (repo, mut cl, make) = setup("reveal.js-unnested");
+    let ps: Vec<_> = packages.iter().map(|p| make.package_at(p)).collect();
+    deduplicate_into_with(repo.path(), &ps, options, &mut cl).unwrap();
+    let destinations = cl.instructions
+        .iter()
+        .map(|i| match *i {
+            InstructionOwned::MoveAndSymlink { ref to_here, .. } => to_here.clone(),
+            _ => unreachable!(),
+        })
+        .collect();
+    (repo, destinations)
+}
+
+#[test]
+fn it_detects_native_addons_and_stores_them_per_platform() {
+    let options = DeduplicationOptions {
+        platform_segment: PlatformSegment::Detect,
+        platform: String::from("linux-x64"),
+        ..Default::default()
+    };
+    let (repo, destinations) = move_destinations(&options, &["node-sass"]);
+    assert_that(&destinations,
+                contains(vec![repo.path().join("node-sass").join("3.3.3").join("linux-x64")]).exactly());
+
+    let (repo, destinations) = move_destinations(&options, &["sigmund"]);
+    assert_that(&destinations,
+                contains(vec![repo.path().join("sigmund").join("1.0.1")]).exactly());
+}
+
+#[test]
+fn it_stores_explicitly_listed_packages_per_platform() {
+    let options = DeduplicationOptions {
+        platform_segment: PlatformSegment::Packages(vec![String::from("sigmund")]),
+        platform: String::from("darwin-x64"),
+        ..Default::default()
+    };
+    let (repo, destinations) = move_destinations(&options, &["sigmund"]);
+    assert_that(&destinations,
+                contains(vec![repo.path().join("sigmund").join("1.0.1").join("darwin-x64")]).exactly());
+}
+
+#[test]
+fn it_produces_the_same_callbacks_when_reading_package_json_files_in_parallel() {
+    let repo = utils::transient_repo_path();
+    let ps: Vec<_> = find_packages(utils::fixture_at("reveal.js-nested")).map(|p| p.unwrap()).collect();
+    let run = |threads| {
+        let mut cl = Collector::default();
+        let options = DeduplicationOptions { threads, ..Default::default() };
+        let r = deduplicate_into_with(repo.path(), &ps, &options, &mut cl);
+        (r.map_err(|errs| errs.len()), cl.preprocessed_packages, cl.instructions)
+    };
+
+    let serial = run(1);
+    assert_that(serial.2.len(), greater_than(100));
+    assert_that(run(8), equal_to(serial));
+}
+
+#[test]
+fn it_orders_instructions_by_name_and_version_with_parents_before_nested_packages() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    for &(dir, name, version) in &[("z", "z", "1.0.0"), ("b", "b", "1.0.0"), ("a", "a", "1.0.0"),
+                                   ("z/node_modules/a", "a", "2.0.0")] {
+        utils::write_package_json(&node_modules.join(dir),
+                                  &format!(r#"{{"name":"{}", "version":"{}"}}"#, name, version));
+    }
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let mut cl = Collector::default();
+    deduplicate_into(repo.path(), &ps, &mut cl).unwrap();
+    let moved: Vec<_> = cl.instructions
+        .iter()
+        .map(|i| match *i {
+            InstructionOwned::MoveAndSymlink { ref from_here, .. } => from_here.strip_prefix(&node_modules).unwrap(),
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_that(moved,
+                equal_to(vec![std::path::Path::new("a"),
+                              std::path::Path::new("b"),
+                              std::path::Path::new("z"),
+                              std::path::Path::new("z/node_modules/a")]));
+}
+
+#[test]
+fn it_replaces_every_copy_of_a_package_with_a_symlink() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    for dir in &["a", "b/node_modules/a", "c/node_modules/a"] {
+        utils::write_package_json(&node_modules.join(dir), r#"{"name":"a", "version":"1.0.0"}"#);
+    }
+    for name in &["b", "c"] {
+        utils::write_package_json(&node_modules.join(name),
+                                  &format!(r#"{{"name":"{}", "version":"1.0.0"}}"#, name));
+    }
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let mut cl = Collector::default();
+    deduplicate_into(repo.path(), &ps, &mut cl).unwrap();
+    let destination = repo.path().join("a").join("1.0.0");
+    let copies: Vec<_> = cl.instructions
+        .iter()
+        .filter_map(|i| match *i {
+            InstructionOwned::MoveAndSymlink { ref from_here, ref to_here, .. } if *to_here == destination => {
+                Some(("move", from_here.clone()))
+            }
+            InstructionOwned::ReplaceWithSymlink { ref this_directory, ref symlink_destination }
+                if *symlink_destination == destination => Some(("replace", this_directory.clone())),
+            _ => None,
+        })
+        .collect();
+    assert_that(copies,
+                equal_to(vec![("move", node_modules.join("a")),
+                              ("replace", node_modules.join("b/node_modules/a")),
+                              ("replace", node_modules.join("c/node_modules/a"))]));
+}
+
+#[test]
+fn it_refuses_to_replace_copies_whose_contents_differ() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    utils::write_package_json(&node_modules.join("a"), r#"{"name":"a", "version":"1.0.0", "_where":"/here"}"#);
+    utils::write_package_json(&node_modules.join("b/node_modules/a"),
+                              r#"{"_where":"/there", "name":"a", "version":"1.0.0"}"#);
+    utils::write_package_json(&node_modules.join("c/node_modules/a"), r#"{"name":"a", "version":"1.0.0"}"#);
+    File::create(node_modules.join("c/node_modules/a/patched.js")).unwrap();
+
+    let ps: Vec<_> = ["a", "b/node_modules/a", "c/node_modules/a"]
+        .iter()
+        .map(|dir| {
+            PackageInfo {
+                directory: node_modules.join(dir),
+                root_directory: node_modules.clone(),
+            }
+        })
+        .collect();
+    for &threads in &[1, 4] {
+        let options = DeduplicationOptions {
+            compare_contents: true,
+            threads,
+            ..Default::default()
+        };
+        let mut cl = Collector::default();
+        let errors = deduplicate_into_with(repo.path(), &ps, &options, &mut cl).unwrap_err();
+
+        assert_that(&cl.instructions, of_len(2));
+        assert_that(&errors, of_len(1));
+        match errors[0] {
+            Error::ContentMismatch(ref p, ref reference) => {
+                assert_that(p, equal_to(&ps[2].directory));
+                assert_that(reference, equal_to(&ps[0].directory));
+            }
+            ref err => panic!("unexpected error: {}", err),
+        }
+    }
+}
+
+fn retarget_instructions(linked_version: &str) -> (TempDir, TempDir, Vec<InstructionOwned>) {
+    retarget_instructions_for(linked_version, r#""dependencies": {"b": "^1.0.0"}"#)
+}
+
+fn retarget_instructions_for(linked_version: &str, dependencies: &str) -> (TempDir, TempDir, Vec<InstructionOwned>) {
+    let repo = utils::transient_repo_path();
+    for version in &["1.0.0", "1.5.0", "2.0.0"] {
+        create_dir_all(repo.path().join("b").join(version)).unwrap();
+    }
+    let project = TempDir::new("project").unwrap();
+    let root = project.path().join("node_modules");
+    let dir = root.join("a");
+    utils::write_package_json(&dir, &format!(r#"{{"version":"1.0.0", "name":"a", {}}}"#, dependencies));
+    create_dir_all(dir.join("node_modules")).unwrap();
+    symlink(repo.path().join("b").join(linked_version),
+            dir.join("node_modules").join("b"))
+        .unwrap();
+
+    let ps = [PackageInfo {
+                  directory: dir,
+                  root_directory: root,
+              }];
+    let mut cl = Collector::default();
+    deduplicate_into(repo.path(), &ps, &mut cl).unwrap();
+    let instructions = cl.instructions
+        .into_iter()
+        .filter(|i| matches!(*i, InstructionOwned::RetargetSymlink { .. }))
+        .collect();
+    (repo, project, instructions)
+}
+
+#[test]
+fn it_retargets_dependency_links_to_the_best_satisfying_version_in_the_repo() {
+    for linked_version in &["1.0.0", "2.0.0"] {
+        let (repo, _project, instructions) = retarget_instructions(linked_version);
+        assert_that(&instructions, of_len(1));
+        match instructions[0] {
+            InstructionOwned::RetargetSymlink { ref this_symlink, ref symlink_destination } => {
+                assert_that(this_symlink.ends_with("a/node_modules/b"), equal_to(true));
+                assert_that(symlink_destination, equal_to(&repo.path().join("b").join("1.5.0")));
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[test]
+fn it_does_not_retarget_dependency_links_which_point_to_the_best_version_already() {
+    let (_repo, _project, instructions) = retarget_instructions("1.5.0");
+    assert_that(&instructions, of_len(0));
+}
+
+fn peer_project(peer_version: &str) -> (TempDir, TempDir, Collector) {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    utils::write_package_json(&node_modules.join("grunt"),
+                              &format!(r#"{{"version":"{}", "name":"grunt"}}"#, peer_version));
+    utils::write_package_json(&node_modules.join("grunt-plugin"),
+                              r#"{"version":"1.0.0", "name":"grunt-plugin",
+                                  "peerDependencies": {"grunt": ">=0.4.0"}}"#);
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let mut cl = Collector::default();
+    deduplicate_into(repo.path(), &ps, &mut cl).ok();
+    (repo, project, cl)
+}
+
+#[test]
+fn it_links_peer_dependencies_of_packages_moved_into_the_repo() {
+    let (repo, _project, cl) = peer_project("1.0.0");
+    assert_that(cl.preprocessed_packages.len(), equal_to(0));
+    assert_that(&cl.instructions, of_len(3));
+    match cl.instructions[2] {
+        InstructionOwned::CreateSymlink { ref this_symlink, ref symlink_destination } => {
+            let expected = repo.path().join("grunt-plugin").join("1.0.0").join("node_modules").join("grunt");
+            assert_that(this_symlink, equal_to(&expected));
+            assert_that(symlink_destination, equal_to(&repo.path().join("grunt").join("1.0.0")));
+        }
+        ref i => panic!("unexpected instruction: {}", i),
+    }
+}
+
+#[test]
+fn it_reports_peer_dependencies_which_cannot_be_satisfied() {
+    let (_repo, _project, cl) = peer_project("0.3.0");
+    assert_that(&cl.instructions, of_len(2));
+    assert_that(cl.preprocessed_packages.len(), equal_to(1));
+    assert_that(cl.preprocessed_packages[0].directory.ends_with("grunt-plugin"), equal_to(true));
+
+    let repo = utils::transient_repo_path();
+    let ps: Vec<_> = find_packages(utils::fixture_at("reveal.js-nested")).map(|p| p.unwrap()).collect();
+    let errors = deduplicate_into(repo.path(), &ps, &mut Collector::default()).unwrap_err();
+    let unsatisfied_grunt = errors.iter()
+        .filter(|err| matches!(**err, Error::UnsatisfiedPeerDependency(_, ref name, _) if name == "grunt"))
+        .count();
+    assert_that(unsatisfied_grunt, greater_than(0));
+}
+
+#[test]
+fn it_retargets_links_to_optional_dependencies_once() {
+    let dependencies = r#""dependencies": {"b": "^1.0.0"}, "optionalDependencies": {"b": "^1.0.0"}"#;
+    let (repo, _project, instructions) = retarget_instructions_for("1.0.0", dependencies);
+    assert_that(&instructions, of_len(1));
+    match instructions[0] {
+        InstructionOwned::RetargetSymlink { ref symlink_destination, .. } => {
+            assert_that(symlink_destination, equal_to(&repo.path().join("b").join("1.5.0")));
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn it_warns_about_optional_dependencies_which_cannot_be_parsed() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    utils::write_package_json(&project.path().join("node_modules").join("a"),
+                              r#"{"version":"1.0.0", "name":"a", "optionalDependencies": {"b": 1, "c": "^^1"}}"#);
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let mut cl = Collector::default();
+    deduplicate_into(repo.path(), &ps, &mut cl).unwrap();
+    assert_that(cl.warnings.len(), equal_to(2));
+    assert_that(cl.preprocessed_packages.len(), equal_to(0));
+    assert_that(&cl.instructions, of_len(1));
+}
+
+#[test]
+fn it_warns_about_and_skips_dependency_names_which_would_escape_the_project() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    utils::write_package_json(&project.path().join("node_modules").join("a"),
+                              r#"{"version":"1.0.0", "name":"a", "dependencies": {"../../evil": "^1.0.0"},
+                                  "peerDependencies": {"/abs": "^1.0.0", "@a/b/c": "^1.0.0"}}"#);
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let options = DeduplicationOptions {
+        repo_layout: Arc::new(PnpmLayout),
+        ..Default::default()
+    };
+    let mut cl = Collector::default();
+    deduplicate_into_with(repo.path(), &ps, &options, &mut cl).unwrap();
+    assert_that(cl.warnings.len(), equal_to(3));
+    assert_that(cl.preprocessed_packages.len(), equal_to(0));
+    assert_that(&cl.instructions, of_len(1));
+}
+
+#[test]
+fn it_retargets_aliases_by_the_name_of_their_package_and_ignores_packages_outside_of_the_registry() {
+    let repo = utils::transient_repo_path();
+    for version in &["1.0.0", "1.5.0"] {
+        create_dir_all(repo.path().join("b").join(version)).unwrap();
+    }
+    let project = TempDir::new("project").unwrap();
+    let dir = project.path().join("node_modules").join("a");
+    utils::write_package_json(&dir,
+                              r#"{"version":"1.0.0", "name":"a", "dependencies": {"alias": "npm:b@^1.0.0",
+                                  "lib": "file:../lib", "tagged": "latest", "forked": "user/repo"}}"#);
+    create_dir_all(dir.join("node_modules")).unwrap();
+    for name in &["alias", "lib"] {
+        symlink(repo.path().join("b").join("1.0.0"), dir.join("node_modules").join(name)).unwrap();
+    }
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let mut cl = Collector::default();
+    deduplicate_into(repo.path(), &ps, &mut cl).unwrap();
+    let retargeted: Vec<_> = cl.instructions
+        .iter()
+        .filter_map(|i| match *i {
+            InstructionOwned::RetargetSymlink { ref this_symlink, ref symlink_destination } => {
+                Some((this_symlink.clone(), symlink_destination.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+    assert_that(&retargeted,
+                contains(vec![(dir.join("node_modules").join("alias"), repo.path().join("b").join("1.5.0"))])
+                    .exactly());
+}
+
+#[test]
+fn it_reports_dependencies_missing_from_the_repo_in_the_pnpm_layout() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    utils::write_package_json(&project.path().join("node_modules").join("a"),
+                              r#"{"version":"1.0.0", "name":"a", "dependencies": {"b": "^1.0.0"},
+                                  "optionalDependencies": {"fsevents": "^2.0.0"}}"#);
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let options = DeduplicationOptions {
+        repo_layout: Arc::new(PnpmLayout),
+        ..Default::default()
+    };
+    let mut cl = Collector::default();
+    let errors = deduplicate_into_with(repo.path(), &ps, &options, &mut cl).unwrap_err();
+    assert_that(&errors, of_len(1));
+    assert_that(matches!(errors[0], Error::UnsatisfiedDependency(_, ref name, _) if name == "b"),
+                equal_to(true));
+    assert_that(cl.warnings.len(), equal_to(1));
+    assert_that(&cl.instructions, of_len(1));
+}
@@ -0,0 +1,360 @@
+#![allow(deprecated)]
+
+extern crate hamcrest;
+extern crate tempdir;
+extern crate npm_tools;
+
+mod utils;
+
+use std::path::Path;
+use std::sync::Arc;
+use npm_tools::{deduplicate_into, deduplicate_into_with, find_packages, DeduplicationOptions, FsExecutor,
+                Error, ExecutionError, FileStrategy, Instruction, LinkMode, Visitor, PackageInfo, Plan, PnpmLayout,
+                SymlinkStyle};
+use hamcrest::*;
+use tempdir::TempDir;
+use std::fs::{self, File, create_dir_all};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+
+/// Create a package named `name` at `version` with a single source file in `root`.
+fn installed_package(root: &Path, name: &str, version: &str) -> PackageInfo {
+    let dir = root.join(name);
+    utils::make_package(&dir, name, version);
+    utils::write_file(&dir.join("lib").join("index.js"), "");
+    PackageInfo {
+        directory: dir,
+        root_directory: root.to_owned(),
+    }
+}
+
+/// Returns a repository located on another device than `project`, which is a tmpfs at `/dev/shm`.
+/// Fails if there is none, as tests using it would not test anything otherwise.
+fn repo_on_other_device(project: &Path) -> TempDir {
+    let shm = Path::new("/dev/shm");
+    let device = |p: &Path| fs::metadata(p).map(|m| m.dev()).ok();
+    assert!(device(shm).is_some() && device(shm) != device(project),
+            "a tmpfs at /dev/shm on another device than the temporary directory is required");
+    TempDir::new_in(shm, "npm_repo_path").unwrap()
+}
+
+#[test]
+fn it_moves_packages_into_the_repo_and_symlinks_them() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let ps = [installed_package(project.path(), "sigmund", "1.0.1")];
+
+    let r = deduplicate_into(repo.path(), &ps, &mut FsExecutor::default());
+    r.unwrap();
+
+    let destination = repo.path().join("sigmund").join("1.0.1");
+    assert_that(destination.join("lib").join("index.js").is_file(), equal_to(true));
+    assert_that(fs::read_link(&ps[0].directory).unwrap(), equal_to(destination));
+}
+
+#[test]
+fn it_replaces_packages_already_in_the_repo_with_a_symlink() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let destination = repo.path().join("sigmund").join("1.0.1");
+    let identical = installed_package(&repo.path().join("sigmund"), "sigmund", "1.0.1");
+    fs::rename(identical.directory, &destination).unwrap();
+    let ps = [installed_package(project.path(), "sigmund", "1.0.1")];
+
+    let r = deduplicate_into(repo.path(), &ps, &mut FsExecutor::default());
+    r.unwrap();
+    assert_that(fs::read_link(&ps[0].directory).unwrap(), equal_to(destination));
+}
+
+#[test]
+fn it_keeps_relative_links_of_nested_packages_valid() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let parent = installed_package(&project.path().join("node_modules"), "parent", "1.0.0");
+    installed_package(&parent.directory.join("node_modules"), "child", "2.0.0");
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let options = DeduplicationOptions {
+        symlink_style: SymlinkStyle::Relative,
+        ..Default::default()
+    };
+    deduplicate_into_with(repo.path(), &ps, &options, &mut FsExecutor::default()).unwrap();
+
+    let child = repo.path().join("parent").join("1.0.0").join("node_modules").join("child");
+    assert_that(fs::read_link(&child).unwrap().is_relative(), equal_to(true));
+    assert_that(child.join("lib").join("index.js").is_file(), equal_to(true));
+}
+
+#[test]
+fn it_keeps_nested_packages_of_copies_which_differ_from_the_first_copy() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    let a = installed_package(&node_modules, "a", "1.0.0");
+    installed_package(&a.directory.join("node_modules"), "x", "1.0.0");
+    let b = installed_package(&node_modules, "b", "1.0.0");
+    let nested_a = installed_package(&b.directory.join("node_modules"), "a", "1.0.0");
+    installed_package(&nested_a.directory.join("node_modules"), "x", "2.0.0");
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    deduplicate_into(repo.path(), &ps, &mut FsExecutor::default()).unwrap();
+
+    for version in &["1.0.0", "2.0.0"] {
+        assert_that(repo.path().join("x").join(version).join("lib").join("index.js").is_file(),
+                    equal_to(true));
+    }
+    assert_that(fs::read_link(&nested_a.directory).unwrap(),
+                equal_to(repo.path().join("a").join("1.0.0")));
+}
+
+#[test]
+fn it_links_peer_dependencies_next_to_packages_within_the_repo() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    installed_package(&node_modules, "grunt", "1.0.0");
+    let plugin = installed_package(&node_modules, "grunt-plugin", "1.0.0");
+    File::create(plugin.directory.join("package.json"))
+        .unwrap()
+        .write_all(br#"{"version":"1.0.0", "name":"grunt-plugin", "peerDependencies": {"grunt": "1.x"}}"#)
+        .unwrap();
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let options = DeduplicationOptions {
+        symlink_style: SymlinkStyle::Relative,
+        ..Default::default()
+    };
+    deduplicate_into_with(repo.path(), &ps, &options, &mut FsExecutor::default()).unwrap();
+
+    let peer = repo.path().join("grunt-plugin").join("1.0.0").join("node_modules").join("grunt");
+    assert_that(fs::read_link(&peer).unwrap().is_relative(), equal_to(true));
+    assert_that(peer.join("lib").join("index.js").is_file(), equal_to(true));
+}
+
+#[test]
+fn it_links_dependencies_next_to_packages_in_the_pnpm_layout() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    installed_package(&node_modules, "dep", "1.0.0");
+    let app = installed_package(&node_modules, "@scope/app", "1.0.0");
+    File::create(app.directory.join("package.json"))
+        .unwrap()
+        .write_all(br#"{"version":"1.0.0", "name":"@scope/app", "dependencies": {"dep": "^1.0.0"},
+                        "devDependencies": {"missing": "1.x"}}"#)
+        .unwrap();
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let options = DeduplicationOptions {
+        repo_layout: Arc::new(PnpmLayout),
+        ..Default::default()
+    };
+    deduplicate_into_with(repo.path(), &ps, &options, &mut FsExecutor::default()).unwrap();
+
+    let app = repo.path().join(".pnpm").join("@scope+app@1.0.0").join("node_modules");
+    let dep = repo.path().join(".pnpm").join("dep@1.0.0").join("node_modules").join("dep");
+    assert_that(fs::read_link(node_modules.join("@scope").join("app")).unwrap(),
+                equal_to(app.join("@scope").join("app")));
+    assert_that(fs::read_link(node_modules.join("dep")).unwrap(), equal_to(dep.clone()));
+    assert_that(fs::read_link(app.join("dep")).unwrap(), equal_to(dep));
+    assert_that(app.join("dep").join("lib").join("index.js").is_file(), equal_to(true));
+    assert_that(app.join("missing").symlink_metadata().is_err(), equal_to(true));
+
+    let mut plan = Plan::new(repo.path(), project.path());
+    deduplicate_into_with(repo.path(), &ps, &options, &mut plan).unwrap();
+    assert_that(plan.instructions.len(), equal_to(0));
+}
+
+#[test]
+fn it_stores_and_reads_back_content_hashes_in_the_pnpm_layout() {
+    let repo = utils::transient_repo_path();
+    create_dir_all(repo.path().join(".npm-tools")).unwrap();
+    let options = DeduplicationOptions {
+        repo_layout: Arc::new(PnpmLayout),
+        compare_contents: true,
+        ..Default::default()
+    };
+    let first = TempDir::new("project").unwrap();
+    let ps = [installed_package(&first.path().join("node_modules"), "a", "1.0.0")];
+    deduplicate_into_with(repo.path(), &ps, &options, &mut FsExecutor::default()).unwrap();
+
+    let stored_hash = repo.path()
+        .join(".npm-tools")
+        .join("hashes")
+        .join(".pnpm")
+        .join("a@1.0.0")
+        .join("node_modules")
+        .join("a");
+    assert_that(stored_hash.is_file(), equal_to(true));
+
+    // An identical copy only matches the package in the repository if the stored hash is used
+    File::create(&stored_hash).unwrap().write_all(b"0000").unwrap();
+    let second = TempDir::new("project").unwrap();
+    let ps = [installed_package(&second.path().join("node_modules"), "a", "1.0.0")];
+    let mut plan = Plan::new(repo.path(), second.path());
+    let errors = deduplicate_into_with(repo.path(), &ps, &options, &mut plan).unwrap_err();
+    assert_that(&errors, of_len(1));
+    match errors[0] {
+        Error::ContentMismatch(ref p, _) => assert_that(p, equal_to(&ps[0].directory)),
+        ref err => panic!("unexpected error: {}", err),
+    }
+}
+
+#[test]
+fn it_copies_packages_across_devices() {
+    let project = TempDir::new("project").unwrap();
+    let repo = repo_on_other_device(project.path());
+    let p = installed_package(project.path(), "sigmund", "1.0.1");
+    let to_here = repo.path().join("sigmund").join("1.0.1");
+
+    FsExecutor::default().change(Instruction::MoveAndSymlink {
+            from_here: &p.directory,
+            to_here: &to_here,
+            symlink_destination: &to_here,
+        })
+        .unwrap();
+
+    assert_that(to_here.join("lib").join("index.js").is_file(), equal_to(true));
+    assert_that(fs::read_link(&p.directory).unwrap(), equal_to(to_here));
+}
+
+#[test]
+fn it_hardlinks_the_files_of_every_copy_in_hardlink_mode() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let ps = [installed_package(&project.path().join("first"), "sigmund", "1.0.1"),
+              installed_package(&project.path().join("second"), "sigmund", "1.0.1")];
+    let options = DeduplicationOptions {
+        link_mode: LinkMode::Hardlink,
+        ..Default::default()
+    };
+
+    deduplicate_into_with(repo.path(), &ps, &options, &mut FsExecutor::default()).unwrap();
+
+    let file = repo.path().join("sigmund").join("1.0.1").join("lib").join("index.js");
+    for p in &ps {
+        assert_that(fs::symlink_metadata(&p.directory).unwrap().is_dir(), equal_to(true));
+        let copy = fs::metadata(p.directory.join("lib").join("index.js")).unwrap();
+        assert_that(copy.ino(), equal_to(fs::metadata(&file).unwrap().ino()));
+    }
+
+    let mut plan = Plan::new(repo.path(), project.path());
+    deduplicate_into_with(repo.path(), &ps, &options, &mut plan).unwrap();
+    assert_that(plan.instructions.len(), equal_to(0));
+}
+
+#[test]
+fn it_copies_files_across_devices_in_hardlink_mode() {
+    let project = TempDir::new("project").unwrap();
+    let repo = repo_on_other_device(project.path());
+    let p = installed_package(project.path(), "sigmund", "1.0.1");
+    let to_here = repo.path().join("sigmund").join("1.0.1");
+
+    FsExecutor::default().change(Instruction::HardlinkFiles {
+            from_here: &p.directory,
+            to_here: &to_here,
+        })
+        .unwrap();
+
+    assert_that(to_here.join("lib").join("index.js").is_file(), equal_to(true));
+    assert_that(p.directory.join("lib").join("index.js").is_file(), equal_to(true));
+}
+
+#[test]
+fn it_clones_files_or_falls_back_to_hardlinks_in_reflink_mode() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let ps = [installed_package(&project.path().join("first"), "sigmund", "1.0.1"),
+              installed_package(&project.path().join("second"), "sigmund", "1.0.1")];
+    let options = DeduplicationOptions {
+        link_mode: LinkMode::Reflink,
+        ..Default::default()
+    };
+
+    let mut executor = FsExecutor::default();
+    deduplicate_into_with(repo.path(), &ps, &options, &mut executor).unwrap();
+
+    let linked = executor.take_linked_files();
+    assert_that(linked.len(), equal_to(4));
+    let destination = repo.path().join("sigmund").join("1.0.1");
+    for file in &linked {
+        let original = match file.path.strip_prefix(&destination) {
+            Ok(relative) => ps[0].directory.join(relative),
+            Err(_) => destination.join(file.path.strip_prefix(&ps[1].directory).unwrap()),
+        };
+        let shared_inode = fs::metadata(&file.path).unwrap().ino() == fs::metadata(&original).unwrap().ino();
+        match file.strategy {
+            FileStrategy::Reflink => assert_that(shared_inode, equal_to(false)),
+            FileStrategy::Hardlink => assert_that(shared_inode, equal_to(true)),
+            FileStrategy::Copy => panic!("unexpected copy of '{}'", file.path.display()),
+        }
+    }
+    assert_that(executor.take_linked_files().len(), equal_to(0));
+}
+
+#[test]
+fn it_falls_back_to_hardlinks_in_reflink_mode_if_files_cannot_be_cloned() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let ps = [installed_package(&project.path().join("first"), "sigmund", "1.0.1"),
+              installed_package(&project.path().join("second"), "sigmund", "1.0.1")];
+    let options = DeduplicationOptions {
+        link_mode: LinkMode::Reflink,
+        ..Default::default()
+    };
+
+    let mut executor = FsExecutor::without_reflinks();
+    deduplicate_into_with(repo.path(), &ps, &options, &mut executor).unwrap();
+
+    let linked = executor.take_linked_files();
+    assert_that(linked.len(), equal_to(4));
+    assert_that(linked.iter().all(|f| f.strategy == FileStrategy::Hardlink), equal_to(true));
+    let file = repo.path().join("sigmund").join("1.0.1").join("lib").join("index.js");
+    for p in &ps {
+        let copy = fs::metadata(p.directory.join("lib").join("index.js")).unwrap();
+        assert_that(copy.ino(), equal_to(fs::metadata(&file).unwrap().ino()));
+    }
+}
+
+#[test]
+fn it_copies_files_across_devices_in_reflink_mode() {
+    let project = TempDir::new("project").unwrap();
+    let p = installed_package(project.path(), "sigmund", "1.0.1");
+
+    for mut executor in [FsExecutor::default(), FsExecutor::without_reflinks()] {
+        let repo = repo_on_other_device(project.path());
+        let to_here = repo.path().join("sigmund").join("1.0.1");
+        executor.change(Instruction::CloneFiles {
+                from_here: &p.directory,
+                to_here: &to_here,
+            })
+            .unwrap();
+
+        let linked = executor.take_linked_files();
+        assert_that(linked.len(), equal_to(2));
+        assert_that(linked.iter().all(|f| f.strategy == FileStrategy::Copy), equal_to(true));
+        assert_that(to_here.join("lib").join("index.js").is_file(), equal_to(true));
+    }
+}
+
+#[test]
+fn it_reports_typed_errors() {
+    let project = TempDir::new("project").unwrap();
+    let missing = project.path().join("missing");
+
+    match FsExecutor::default().change(Instruction::ReplaceWithSymlink {
+        this_directory: &missing,
+        symlink_destination: project.path(),
+    }) {
+        Err(ExecutionError::Move(ref p, _, _)) => assert_that(p, equal_to(&missing)),
+        _ => unreachable!(),
+    }
+    assert_that(fs::read_dir(project.path()).unwrap().count(), equal_to(0));
+    match FsExecutor::default().change(Instruction::HardlinkFiles {
+        from_here: &missing,
+        to_here: &project.path().join("copy"),
+    }) {
+        Err(ExecutionError::ReadDirectory(ref p, _)) => assert_that(p, equal_to(&missing)),
+        _ => unreachable!(),
+    }
+}
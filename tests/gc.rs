@@ -0,0 +1,116 @@
+#![allow(deprecated)]
+
+extern crate hamcrest;
+extern crate tempdir;
+extern crate npm_tools;
+
+mod utils;
+
+use std::path::Path;
+use npm_tools::{deduplicate_into_with, find_packages, gc, gc_report, register_project, registered_projects,
+                unregister_project, DeduplicationOptions, Error, FsExecutor, LinkMode};
+use hamcrest::*;
+use tempdir::TempDir;
+use std::fs::{File, create_dir_all};
+use std::io::Write;
+use std::os::unix::fs::symlink;
+use std::thread;
+
+fn make_repo_package(repo: &Path, name: &str, version: &str, content: &[u8]) {
+    let dir = repo.join(name).join(version);
+    create_dir_all(&dir).unwrap();
+    File::create(dir.join("index.js")).unwrap().write_all(content).unwrap();
+}
+
+#[test]
+fn projects_can_be_registered_and_unregistered() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+
+    register_project(repo.path(), project.path()).unwrap();
+    register_project(repo.path(), project.path()).unwrap();
+    assert_that(&registered_projects(repo.path()).unwrap(), of_len(1));
+
+    unregister_project(repo.path(), project.path()).unwrap();
+    assert_that(&registered_projects(repo.path()).unwrap(), of_len(0));
+}
+
+#[test]
+fn projects_registered_concurrently_are_all_kept() {
+    let repo = utils::transient_repo_path();
+    let projects: Vec<_> = (0..8).map(|_| TempDir::new("project").unwrap()).collect();
+
+    let threads: Vec<_> = projects.iter()
+        .map(|p| {
+            let (repo, project) = (repo.path().to_owned(), p.path().to_owned());
+            thread::spawn(move || register_project(repo, project).unwrap())
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_that(&registered_projects(repo.path()).unwrap(), of_len(projects.len()));
+}
+
+#[test]
+fn it_collects_packages_which_are_not_linked_by_any_registered_project() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    make_repo_package(repo.path(), "a", "1.0.0", b"used");
+    make_repo_package(repo.path(), "a", "2.0.0", b"unused");
+    make_repo_package(repo.path(), "@scope/b", "1.0.0", b"used via a");
+    create_dir_all(repo.path().join("a").join("1.0.0").join("node_modules").join("@scope")).unwrap();
+    symlink(repo.path().join("@scope").join("b").join("1.0.0"),
+            repo.path().join("a").join("1.0.0").join("node_modules").join("@scope").join("b"))
+        .unwrap();
+    create_dir_all(project.path().join("node_modules")).unwrap();
+    symlink(repo.path().join("a").join("1.0.0"),
+            project.path().join("node_modules").join("a"))
+        .unwrap();
+    register_project(repo.path(), project.path()).unwrap();
+
+    let report = gc_report(repo.path()).unwrap();
+    assert_that(&report.unused, of_len(1));
+    assert_that(&report.unused[0].name, equal_to(&String::from("a")));
+    assert_that(&report.unused[0].version, equal_to(&String::from("2.0.0")));
+    assert_that(report.reclaimable_bytes(), equal_to(6));
+
+    gc(repo.path(), &mut FsExecutor::default()).unwrap();
+    assert_that(repo.path().join("a").join("2.0.0").exists(), equal_to(false));
+    assert_that(repo.path().join("a").join("1.0.0").exists(), equal_to(true));
+    assert_that(&gc_report(repo.path()).unwrap().unused, of_len(0));
+}
+
+#[test]
+fn it_refuses_to_collect_without_registered_projects() {
+    let repo = utils::transient_repo_path();
+    make_repo_package(repo.path(), "a", "1.0.0", b"unused?");
+
+    for errors in &[gc_report(repo.path()).unwrap_err(), gc(repo.path(), &mut FsExecutor::default()).unwrap_err()] {
+        assert_that(errors.len(), equal_to(1));
+        assert_that(matches!(errors[0], Error::NoRegisteredProjects(..)), equal_to(true));
+    }
+    assert_that(repo.path().join("a").join("1.0.0").exists(), equal_to(true));
+}
+
+#[test]
+fn it_registers_deduplicated_projects_and_keeps_packages_they_hard_link() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    utils::make_package(&project.path().join("node_modules").join("a"), "a", "1.0.0");
+    make_repo_package(repo.path(), "b", "1.0.0", b"unused");
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let options = DeduplicationOptions {
+        link_mode: LinkMode::Hardlink,
+        register_projects: true,
+        ..Default::default()
+    };
+    deduplicate_into_with(repo.path(), &ps, &options, &mut FsExecutor::default()).unwrap();
+    assert_that(registered_projects(repo.path()).unwrap(),
+                equal_to(vec![project.path().canonicalize().unwrap()]));
+
+    let report = gc_report(repo.path()).unwrap();
+    assert_that(&report.unused, of_len(1));
+    assert_that(&*report.unused[0].name, equal_to("b"));
+}
@@ -0,0 +1,170 @@
+#![allow(deprecated)]
+
+extern crate hamcrest;
+extern crate tempdir;
+extern crate npm_tools;
+
+mod utils;
+
+use std::path::{Path, PathBuf};
+use npm_tools::{deduplicate_into, recover, find_packages, ExecutionError, FileStrategy, Instruction, JournaledExecutor,
+                Recovery, Visitor};
+use hamcrest::*;
+use tempdir::TempDir;
+use std::fs::{self, File, create_dir_all};
+use std::io::Write;
+use std::os::unix::fs::symlink;
+
+/// Write a journal to `repo` which contains the single `instruction`, of which `steps_done` steps
+/// were applied.
+fn write_journal(repo: &Path, instruction: Instruction, steps_done: usize) {
+    let mut lines = vec![format!(r#"{{"id":0,"event":"begin","instruction":{}}}"#, instruction.to_json())];
+    for step in 0..steps_done {
+        lines.push(format!(r#"{{"id":0,"event":"start","step":{}}}"#, step));
+        lines.push(format!(r#"{{"id":0,"event":"done","step":{}}}"#, step));
+    }
+    create_dir_all(repo.join(".npm-tools")).unwrap();
+    let mut f = File::create(repo.join(".npm-tools").join("journal")).unwrap();
+    for line in lines {
+        writeln!(f, "{}", line).unwrap();
+    }
+    // a partially written entry, as left behind if the process dies while writing
+    write!(f, r#"{{"id":0,"event":"sta"#).unwrap();
+}
+
+fn crashed_project(repo: &Path, steps_done: usize) -> (TempDir, PathBuf, PathBuf) {
+    let project = TempDir::new("project").unwrap();
+    let from = project.path().join("node_modules").join("a");
+    let to = repo.join("a").join("1.0.0");
+    utils::make_package(&from, "a", "1.0.0");
+    if steps_done > 0 {
+        symlink(&to, project.path().join("node_modules").join(".a.npm-tools-tmp")).unwrap();
+    }
+    if steps_done > 1 {
+        create_dir_all(to.parent().unwrap()).unwrap();
+        fs::rename(&from, &to).unwrap();
+    }
+    write_journal(repo,
+                  Instruction::MoveAndSymlink {
+                      from_here: &from,
+                      to_here: &to,
+                      symlink_destination: &to,
+                  },
+                  steps_done);
+    (project, from, to)
+}
+
+#[test]
+fn it_journals_changes_and_has_nothing_to_recover_afterwards() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    utils::make_package(&project.path().join("node_modules").join("a"), "a", "1.0.0");
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let mut executor = JournaledExecutor::new(repo.path()).unwrap();
+    deduplicate_into(repo.path(), &ps, &mut executor).unwrap();
+
+    let link = project.path().join("node_modules").join("a");
+    assert_that(fs::read_link(&link).unwrap(), equal_to(repo.path().join("a").join("1.0.0")));
+    assert_that(fs::read_dir(project.path().join("node_modules")).unwrap().count(), equal_to(1));
+    assert_that(recover(repo.path()).unwrap().len(), equal_to(0));
+}
+
+#[test]
+fn it_finishes_instructions_which_took_effect() {
+    let repo = utils::transient_repo_path();
+    let (_project, from, to) = crashed_project(repo.path(), 2);
+    assert_that(from.symlink_metadata().is_err(), equal_to(true));
+
+    let recovered = recover(repo.path()).unwrap();
+    assert_that(recovered.len(), equal_to(1));
+    assert_that(recovered[0].recovery, equal_to(Recovery::Finished));
+    assert_that(fs::read_link(&from).unwrap(), equal_to(to));
+    assert_that(from.join("package.json").is_file(), equal_to(true));
+    assert_that(repo.path().join(".npm-tools").join("journal").exists(), equal_to(false));
+}
+
+#[test]
+fn it_rolls_back_instructions_which_did_not_take_effect() {
+    let repo = utils::transient_repo_path();
+    let (project, from, to) = crashed_project(repo.path(), 1);
+
+    let recovered = recover(repo.path()).unwrap();
+    assert_that(recovered.len(), equal_to(1));
+    assert_that(recovered[0].recovery, equal_to(Recovery::RolledBack));
+    assert_that(from.join("package.json").is_file(), equal_to(true));
+    assert_that(to.exists(), equal_to(false));
+    assert_that(fs::read_dir(project.path().join("node_modules")).unwrap().count(), equal_to(1));
+}
+
+#[test]
+fn it_refuses_to_journal_on_top_of_unfinished_instructions() {
+    let repo = utils::transient_repo_path();
+    let (_project, _, _) = crashed_project(repo.path(), 1);
+
+    match JournaledExecutor::new(repo.path()) {
+        Err(ExecutionError::UnfinishedJournal(_)) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("expected an error"),
+    }
+    recover(repo.path()).unwrap();
+    let mut executor = JournaledExecutor::new(repo.path()).unwrap();
+    let link = repo.path().join("link");
+    assert_that(executor.change(Instruction::RemoveSymlink { this_symlink: &link }).is_err(), equal_to(true));
+    assert_that(recover(repo.path()).unwrap().len(), equal_to(0));
+}
+
+#[test]
+fn it_locks_the_journal_while_an_executor_uses_it() {
+    let repo = utils::transient_repo_path();
+    let executor = JournaledExecutor::new(repo.path()).unwrap();
+    match JournaledExecutor::new(repo.path()) {
+        Err(ExecutionError::JournalInUse(_)) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("expected an error"),
+    }
+    let (_project, _, _) = crashed_project(repo.path(), 1);
+    assert_that(matches!(recover(repo.path()), Err(ExecutionError::JournalInUse(_))), equal_to(true));
+
+    drop(executor);
+    assert_that(recover(repo.path()).unwrap().len(), equal_to(1));
+    assert_that(JournaledExecutor::new(repo.path()).is_ok(), equal_to(true));
+}
+
+#[test]
+fn it_does_not_apply_steps_again_which_were_done() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let (from, to) = (project.path().join("gone"), project.path().join("linked"));
+    utils::make_package(&to, "a", "1.0.0");
+    write_journal(repo.path(),
+                  Instruction::HardlinkFiles {
+                      from_here: &from,
+                      to_here: &to,
+                  },
+                  1);
+
+    let recovered = recover(repo.path()).unwrap();
+    assert_that(recovered.len(), equal_to(1));
+    assert_that(recovered[0].recovery, equal_to(Recovery::Finished));
+    assert_that(to.join("package.json").is_file(), equal_to(true));
+}
+
+#[test]
+fn it_hardlinks_files_instead_of_cloning_them_without_reflinks() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let from = project.path().join("a");
+    utils::make_package(&from, "a", "1.0.0");
+    let to = repo.path().join("a").join("1.0.0");
+
+    let mut executor = JournaledExecutor::without_reflinks(repo.path()).unwrap();
+    executor.change(Instruction::CloneFiles {
+            from_here: &from,
+            to_here: &to,
+        })
+        .unwrap();
+    let linked = executor.take_linked_files();
+    assert_that(linked.len(), equal_to(1));
+    assert_that(linked[0].strategy, equal_to(FileStrategy::Hardlink));
+}
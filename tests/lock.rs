@@ -0,0 +1,150 @@
+#![allow(deprecated)]
+
+extern crate hamcrest;
+extern crate tempdir;
+extern crate npm_tools;
+
+mod utils;
+
+use std::fs::create_dir_all;
+use std::path::Path;
+use npm_tools::{Error, LockDiscrepancy, LockedPackage, cross_check_lock, find_locked_packages, find_packages};
+use hamcrest::*;
+use tempdir::TempDir;
+
+fn summary(packages: &[LockedPackage], root: &Path) -> Vec<(String, String, String)> {
+    packages.iter()
+        .map(|p| {
+            (p.info.directory.strip_prefix(root).unwrap().to_string_lossy().into_owned(),
+             p.name.clone(),
+             p.version.clone())
+        })
+        .collect()
+}
+
+fn expected(entries: &[(&str, &str, &str)]) -> Vec<(String, String, String)> {
+    entries.iter().map(|&(d, n, v)| (d.to_owned(), n.to_owned(), v.to_owned())).collect()
+}
+
+const PACKAGES: &str = r#"{
+    "": {"name": "project", "version": "1.0.0"},
+    "node_modules/a-b": {"version": "1.0.0"},
+    "node_modules/a": {"version": "2.0.0", "resolved": "https://registry.npmjs.org/a/-/a-2.0.0.tgz",
+                       "integrity": "sha512-abc"},
+    "node_modules/a/node_modules/b": {"version": "1.1.0"},
+    "node_modules/@scope/c": {"version": "3.0.0"},
+    "node_modules/alias": {"name": "real", "version": "4.0.0"},
+    "node_modules/linked": {"resolved": "packages/linked", "link": true},
+    "packages/linked": {"name": "linked", "version": "0.1.0"}
+}"#;
+
+#[test]
+fn it_reads_the_packages_of_lockfiles_of_version_2_and_3() {
+    for &version in &[2, 3] {
+        let project = TempDir::new("project").unwrap();
+        let root = project.path();
+        utils::write_file(&root.join("package-lock.json"),
+                          &format!(r#"{{"lockfileVersion": {}, "packages": {}}}"#, version, PACKAGES));
+
+        let locked = find_locked_packages(root).unwrap();
+        assert_that(summary(&locked, root),
+                    equal_to(expected(&[("node_modules/@scope/c", "@scope/c", "3.0.0"),
+                                        ("node_modules/a", "a", "2.0.0"),
+                                        ("node_modules/a/node_modules/b", "b", "1.1.0"),
+                                        ("node_modules/a-b", "a-b", "1.0.0"),
+                                        ("node_modules/alias", "real", "4.0.0")])));
+        let a = &locked[1];
+        assert_that(a.resolved.as_deref(), equal_to(Some("https://registry.npmjs.org/a/-/a-2.0.0.tgz")));
+        assert_that(a.integrity.as_deref(), equal_to(Some("sha512-abc")));
+        assert_that(&a.info.root_directory, equal_to(&root.join("node_modules")));
+        assert_that(locked[0].resolved.is_none(), equal_to(true));
+    }
+}
+
+#[test]
+fn it_reads_the_nested_dependencies_of_lockfiles_of_version_1() {
+    let project = TempDir::new("project").unwrap();
+    let root = project.path();
+    utils::write_file(&root.join("package-lock.json"),
+                      r#"{"lockfileVersion": 1, "dependencies": {
+                            "a": {"version": "2.0.0", "integrity": "sha1-abc", "dependencies": {
+                                "b": {"version": "1.1.0"}
+                            }},
+                            "@scope/c": {"version": "3.0.0"},
+                            "alias": {"version": "npm:@scope/real@4.0.0"},
+                            "local": {"version": "file:../local"}
+                         }}"#);
+
+    let locked = find_locked_packages(root).unwrap();
+    assert_that(summary(&locked, root),
+                equal_to(expected(&[("node_modules/@scope/c", "@scope/c", "3.0.0"),
+                                    ("node_modules/a", "a", "2.0.0"),
+                                    ("node_modules/a/node_modules/b", "b", "1.1.0"),
+                                    ("node_modules/alias", "@scope/real", "4.0.0")])));
+    assert_that(locked[1].integrity.as_deref(), equal_to(Some("sha1-abc")));
+}
+
+#[test]
+fn it_prefers_the_shrinkwrap_and_rejects_unknown_lockfiles() {
+    let project = TempDir::new("project").unwrap();
+    let root = project.path();
+    match find_locked_packages(root) {
+        Err(Error::Lockfile(ref p, _)) => assert_that(p, equal_to(&root.join("package-lock.json"))),
+        _ => unreachable!(),
+    }
+
+    utils::write_file(&root.join("package-lock.json"), r#"{"lockfileVersion": 4, "packages": {}}"#);
+    assert_that(matches!(find_locked_packages(root), Err(Error::Lockfile(..))), equal_to(true));
+
+    utils::write_file(&root.join("npm-shrinkwrap.json"),
+                      r#"{"lockfileVersion": 3, "packages": {"node_modules/a": {"version": "1.0.0"}}}"#);
+    assert_that(&find_locked_packages(root).unwrap(), of_len(1));
+}
+
+#[test]
+fn it_rejects_lockfiles_locating_packages_outside_of_node_modules() {
+    let project = TempDir::new("project").unwrap();
+    let root = project.path();
+    for packages in &[r#""node_modules/../../evil": {"version": "1.0.0"}"#,
+                      r#""node_modules/a/../../../evil": {"version": "1.0.0"}"#,
+                      r#""node_modules//abs": {"version": "1.0.0"}"#,
+                      r#""node_modules/a/b": {"version": "1.0.0"}"#,
+                      r#""node_modules/a/node_modules/": {"version": "1.0.0"}"#,
+                      r#""node_modules/alias": {"name": "../evil", "version": "1.0.0"}"#] {
+        utils::write_file(&root.join("package-lock.json"),
+                          &format!(r#"{{"lockfileVersion": 3, "packages": {{{}}}}}"#, packages));
+        assert_that(matches!(find_locked_packages(root), Err(Error::Lockfile(..))), equal_to(true));
+    }
+    for dependencies in &[r#""../evil": {"version": "1.0.0"}"#,
+                          r#""a": {"version": "1.0.0", "dependencies": {"@a/b/c": {"version": "1.0.0"}}}"#,
+                          r#""alias": {"version": "npm:../evil@1.0.0"}"#] {
+        utils::write_file(&root.join("package-lock.json"),
+                          &format!(r#"{{"lockfileVersion": 1, "dependencies": {{{}}}}}"#, dependencies));
+        assert_that(matches!(find_locked_packages(root), Err(Error::Lockfile(..))), equal_to(true));
+    }
+}
+
+#[test]
+fn it_reports_packages_missing_from_either_the_lockfile_or_the_disk() {
+    let project = TempDir::new("project").unwrap();
+    let root = project.path();
+    utils::write_file(&root.join("package-lock.json"),
+                      r#"{"lockfileVersion": 3, "packages": {
+                            "node_modules/a": {"version": "1.0.0"},
+                            "node_modules/b": {"version": "1.0.0"}
+                         }}"#);
+    for name in &["a", "c"] {
+        let dir = root.join("node_modules").join(name);
+        create_dir_all(&dir).unwrap();
+        utils::write_file(&dir.join("package.json"), r#"{"name": "x", "version": "1.0.0"}"#);
+    }
+
+    let locked = find_locked_packages(root).unwrap();
+    let on_disk: Vec<_> = find_packages(root).map(Result::unwrap).collect();
+    let discrepancies = cross_check_lock(&locked, &on_disk);
+    assert_that(discrepancies.len(), equal_to(2));
+    assert_that(discrepancies[0].clone(), equal_to(LockDiscrepancy::NotOnDisk(locked[1].clone())));
+    assert_that(discrepancies[1].clone(), equal_to(LockDiscrepancy::NotInLock(on_disk[1].clone())));
+
+    assert_that(&cross_check_lock(&locked[..1], &on_disk[..1]), of_len(0));
+}
@@ -0,0 +1,113 @@
+#![allow(deprecated)]
+
+extern crate hamcrest;
+extern crate tempdir;
+extern crate serde_json;
+extern crate npm_tools;
+
+mod utils;
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use npm_tools::{deduplicate_into, apply_plan, find_packages, FsExecutor, InstructionOwned, Plan, PlannedPackage,
+                Error, registered_projects};
+use hamcrest::*;
+use tempdir::TempDir;
+use std::fs;
+
+fn planned_project(repo: &Path) -> (TempDir, Plan) {
+    let project = TempDir::new("project").unwrap();
+    utils::make_package(&project.path().join("node_modules").join("a"), "a", "1.0.0");
+    utils::make_package(&project.path().join("node_modules").join("b"), "b", "2.0.0");
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    let mut plan = Plan::new(repo, project.path());
+    deduplicate_into(repo, &ps, &mut plan).unwrap();
+    (project, plan)
+}
+
+#[test]
+fn it_serializes_plans_to_json_and_back() {
+    let repo = utils::transient_repo_path();
+    let (_project, plan) = planned_project(repo.path());
+    assert_that(plan.instructions.len(), equal_to(2));
+
+    let json = serde_json::to_string(&plan).unwrap();
+    let restored: Plan = serde_json::from_str(&json).unwrap();
+    assert_that(&restored, equal_to(&plan));
+
+    let invalid = json.replace("MoveAndSymlink", "Teleport");
+    assert_that(serde_json::from_str::<Plan>(&invalid).is_err(), equal_to(true));
+}
+
+#[test]
+fn it_serializes_paths_which_are_not_valid_utf8_without_loss() {
+    let repo = utils::transient_repo_path();
+    let mut plan = Plan::new(repo.path(), Path::new(OsStr::from_bytes(b"/project-\xff")));
+    let link = plan.project.join("node_modules").join(OsStr::from_bytes(b"\xfe"));
+    plan.instructions.push(InstructionOwned::RemoveSymlink { this_symlink: link.clone() });
+    plan.packages.push(PlannedPackage {
+        directory: link,
+        name: String::from("a"),
+        version: String::from("1.0.0"),
+    });
+
+    let restored: Plan = serde_json::from_str(&serde_json::to_string(&plan).unwrap()).unwrap();
+    assert_that(&restored, equal_to(&plan));
+}
+
+#[test]
+fn it_applies_plans_if_the_filesystem_still_matches() {
+    let repo = utils::transient_repo_path();
+    let (project, plan) = planned_project(repo.path());
+    let plan: Plan = serde_json::from_str(&serde_json::to_string(&plan).unwrap()).unwrap();
+    assert_that(&registered_projects(repo.path()).unwrap(), of_len(0));
+
+    apply_plan(&plan, &mut FsExecutor::default()).unwrap();
+    assert_that(&registered_projects(repo.path()).unwrap(), of_len(1));
+    for name in &["a", "b"] {
+        let link = project.path().join("node_modules").join(name);
+        assert_that(fs::symlink_metadata(&link).unwrap().file_type().is_symlink(), equal_to(true));
+        assert_that(link.join("package.json").is_file(), equal_to(true));
+    }
+}
+
+#[test]
+fn it_refuses_to_apply_stale_plans() {
+    let repo = utils::transient_repo_path();
+    let (project, plan) = planned_project(repo.path());
+    let removed = project.path().join("node_modules").join("b");
+    fs::remove_dir_all(&removed).unwrap();
+
+    let errors = apply_plan(&plan, &mut FsExecutor::default()).unwrap_err();
+    assert_that(errors.len(), equal_to(1));
+    match errors[0] {
+        Error::StalePlan(ref p, _) => assert_that(p, equal_to(&removed)),
+        ref err => panic!("unexpected error: {}", err),
+    }
+    match plan.instructions[0] {
+        InstructionOwned::MoveAndSymlink { ref from_here, .. } => assert_that(from_here.is_dir(), equal_to(true)),
+        ref i => panic!("unexpected instruction: {}", i),
+    }
+}
+
+#[test]
+fn it_refuses_to_apply_plans_whose_packages_were_updated() {
+    let repo = utils::transient_repo_path();
+    let (project, plan) = planned_project(repo.path());
+    let updated = project.path().join("node_modules").join("a");
+    utils::make_package(&updated, "a", "2.0.0");
+    let plan: Plan = serde_json::from_str(&serde_json::to_string(&plan).unwrap()).unwrap();
+
+    let errors = apply_plan(&plan, &mut FsExecutor::default()).unwrap_err();
+    assert_that(errors.len(), equal_to(1));
+    match errors[0] {
+        Error::StalePlan(ref p, ref expectation) => {
+            assert_that(p, equal_to(&updated));
+            assert_that(&**expectation, equal_to("expected package a@1.0.0"));
+        }
+        ref err => panic!("unexpected error: {}", err),
+    }
+    assert_that(repo.path().join("a").exists(), equal_to(false));
+}
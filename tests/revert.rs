@@ -0,0 +1,122 @@
+#![allow(deprecated)]
+
+extern crate hamcrest;
+extern crate tempdir;
+extern crate npm_tools;
+
+mod utils;
+
+use std::path::{Path, PathBuf};
+use npm_tools::{deduplicate_into, revert, find_packages, FsExecutor, Instruction, InstructionOwned, Visitor,
+                PackageInfo, Error};
+use hamcrest::*;
+use tempdir::TempDir;
+use std::fs::{self, File, create_dir_all};
+use std::io::Write;
+use std::os::unix::fs::symlink;
+
+#[derive(Default)]
+struct Collector {
+    instructions: Vec<InstructionOwned>,
+}
+
+impl Visitor for Collector {
+    type Error = Error;
+
+    fn error(&mut self, _: &PackageInfo, _: &Error) {}
+
+    fn change(&mut self, action: Instruction) -> Result<(), Self::Error> {
+        self.instructions.push(action.into());
+        Ok(())
+    }
+}
+
+#[test]
+fn it_copies_linked_packages_back_from_the_repo() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    utils::make_package(&node_modules.join("a"), "a", "1.0.0");
+    utils::make_package(&node_modules.join("a").join("node_modules").join("b"), "b", "2.0.0");
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    deduplicate_into(repo.path(), &ps, &mut FsExecutor::default()).unwrap();
+    assert_that(fs::symlink_metadata(node_modules.join("a")).unwrap().file_type().is_symlink(),
+                equal_to(true));
+
+    let r = revert(project.path(), repo.path(), &mut FsExecutor::default());
+    r.unwrap();
+
+    for dir in &[node_modules.join("a"), node_modules.join("a").join("node_modules").join("b")] {
+        assert_that(fs::symlink_metadata(dir).unwrap().is_dir(), equal_to(true));
+        assert_that(dir.join("package.json").is_file(), equal_to(true));
+    }
+    assert_that(repo.path().join("a").join("1.0.0").join("package.json").is_file(),
+                equal_to(true));
+}
+
+#[test]
+fn it_ignores_symlinks_which_do_not_point_into_the_repo() {
+    let repo = utils::transient_repo_path();
+    let elsewhere = TempDir::new("elsewhere").unwrap();
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    create_dir_all(&node_modules).unwrap();
+    utils::make_package(&repo.path().join("@scope").join("a").join("1.0.0"), "@scope/a", "1.0.0");
+    create_dir_all(node_modules.join("@scope")).unwrap();
+    symlink(repo.path().join("@scope").join("a").join("1.0.0"),
+            node_modules.join("@scope").join("a"))
+        .unwrap();
+    symlink(elsewhere.path(), node_modules.join("b")).unwrap();
+
+    let mut cl = Collector::default();
+    revert(project.path(), repo.path(), &mut cl).unwrap();
+    assert_that(&cl.instructions, of_len(1));
+    match cl.instructions[0] {
+        InstructionOwned::CopyBackFromRepo { ref this_symlink, .. } => {
+            assert_that(this_symlink, equal_to(&node_modules.join("@scope").join("a")))
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Returns all entries below `dir` along with the contents of files and the destination of links.
+fn tree(dir: &Path) -> Vec<(PathBuf, String)> {
+    let mut entries = Vec::new();
+    let mut paths: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+    paths.sort();
+    for path in paths {
+        let file_type = fs::symlink_metadata(&path).unwrap().file_type();
+        if file_type.is_symlink() {
+            entries.push((path.clone(), format!("-> {}", fs::read_link(&path).unwrap().display())));
+        } else if file_type.is_dir() {
+            entries.push((path.clone(), String::from("/")));
+            entries.extend(tree(&path));
+        } else {
+            entries.push((path.clone(), fs::read_to_string(&path).unwrap()));
+        }
+    }
+    entries
+}
+
+#[test]
+fn it_restores_the_original_tree_when_reverting_a_deduplicated_project() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    utils::make_package(&node_modules.join("peer"), "peer", "1.0.0");
+    utils::make_package(&node_modules.join("a").join("node_modules").join("b"), "b", "2.0.0");
+    File::create(node_modules.join("a").join("package.json"))
+        .unwrap()
+        .write_all(br#"{"version":"1.0.0", "name":"a", "peerDependencies": {"peer": "1.x"}}"#)
+        .unwrap();
+    let original = tree(project.path());
+
+    let ps: Vec<_> = find_packages(project.path()).map(|p| p.unwrap()).collect();
+    deduplicate_into(repo.path(), &ps, &mut FsExecutor::default()).unwrap();
+    let peer_link = repo.path().join("a").join("1.0.0").join("node_modules").join("peer");
+    assert_that(fs::symlink_metadata(&peer_link).unwrap().file_type().is_symlink(), equal_to(true));
+
+    revert(project.path(), repo.path(), &mut FsExecutor::default()).unwrap();
+    assert_that(tree(project.path()), equal_to(original));
+}
@@ -0,0 +1,64 @@
+#![allow(deprecated)]
+
+extern crate hamcrest;
+extern crate npm_tools;
+
+use std::path::PathBuf;
+use npm_tools::{DependencySpec, RangeParseError};
+use hamcrest::*;
+
+fn parse(spec: &str) -> DependencySpec {
+    DependencySpec::parse(spec).unwrap()
+}
+
+#[test]
+fn it_classifies_registry_specifiers() {
+    assert_that(matches!(parse("^1.2.0"), DependencySpec::Range(_)), equal_to(true));
+    assert_that(matches!(parse(""), DependencySpec::Range(_)), equal_to(true));
+    assert_that(parse("latest"), equal_to(DependencySpec::Tag(String::from("latest"))));
+    assert_that(parse("next-2.x"), equal_to(DependencySpec::Tag(String::from("next-2.x"))));
+    assert_that(DependencySpec::parse("^^1").is_err(), equal_to(true));
+}
+
+#[test]
+fn it_classifies_aliases_along_with_their_target() {
+    match parse("npm:@scope/other@^1.0.0") {
+        DependencySpec::Alias { ref name, ref spec } => {
+            assert_that(name.as_str(), equal_to("@scope/other"));
+            assert_that(matches!(**spec, DependencySpec::Range(_)), equal_to(true));
+        }
+        spec => panic!("unexpected spec: {}", spec),
+    }
+    let alias = parse("npm:other");
+    assert_that(alias.to_string(), equal_to(String::from("npm:other@latest")));
+    assert_that(alias.registry_range("alias"), equal_to(None));
+
+    let alias = parse("npm:other@1.x");
+    assert_that(alias.registry_range("alias").map(|(name, _)| name), equal_to(Some("other")));
+    assert_that(parse(&alias.to_string()), equal_to(alias));
+}
+
+#[test]
+fn it_rejects_aliases_of_invalid_package_names() {
+    for &(spec, name) in &[("npm:../../evil@1.0.0", "../../evil"),
+                           ("npm:/abs@^1.0.0", "/abs"),
+                           ("npm:@a/b/c@1.x", "@a/b/c"),
+                           ("npm:@scope", "@scope"),
+                           ("npm:", "")] {
+        assert_that(DependencySpec::parse(spec),
+                    equal_to(Err(RangeParseError::InvalidAliasName(name.to_owned()))));
+    }
+}
+
+#[test]
+fn it_classifies_specifiers_of_packages_outside_of_the_registry() {
+    for spec in &["git+https://example.com/repo.git#v1.0.0", "git://example.com/repo.git", "github:user/repo",
+                  "user/repo", "user/repo#semver:^1.0.0"] {
+        assert_that(parse(spec), equal_to(DependencySpec::Git(spec.to_string())));
+    }
+    assert_that(parse("file:../lib"), equal_to(DependencySpec::File(PathBuf::from("../lib"))));
+    assert_that(parse("./lib"), equal_to(DependencySpec::File(PathBuf::from("./lib"))));
+    let url = "https://example.com/package.tgz";
+    assert_that(parse(url), equal_to(DependencySpec::Url(url.to_owned())));
+    assert_that(parse("file:../lib").registry_range("lib"), equal_to(None));
+}
@@ -1,21 +1,27 @@
+use std::fs::{File, create_dir_all};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use npm_tools::PackageInfo;
 use tempdir::TempDir;
 
+#[allow(dead_code)]
 pub fn fixture_at<P>(path: P) -> PathBuf
     where P: AsRef<Path>
 {
     Path::new(file!()).parent().unwrap().parent().unwrap().join("fixtures").join(path)
 }
 
+#[allow(dead_code)]
 pub fn transient_repo_path() -> TempDir {
     TempDir::new("npm_repo_path").unwrap()
 }
 
+#[allow(dead_code)]
 pub struct PackageMaker {
     root: PathBuf,
 }
 
+#[allow(dead_code)]
 impl PackageMaker {
     pub fn new(root: &str) -> PackageMaker {
         PackageMaker { root: PathBuf::from(root).join("node_modules") }
@@ -28,3 +34,22 @@ impl PackageMaker {
         }
     }
 }
+
+/// Write `contents` to the file at `path`, creating the directories containing it.
+#[allow(dead_code)]
+pub fn write_file(path: &Path, contents: &str) {
+    create_dir_all(path.parent().unwrap()).unwrap();
+    File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+}
+
+/// Write a package.json with the given `contents` into `dir`.
+#[allow(dead_code)]
+pub fn write_package_json(dir: &Path, contents: &str) {
+    write_file(&dir.join("package.json"), contents);
+}
+
+/// Create a package named `name` at `version` in `dir`.
+#[allow(dead_code)]
+pub fn make_package(dir: &Path, name: &str, version: &str) {
+    write_package_json(dir, &format!(r#"{{"version":"{}", "name":"{}"}}"#, version, name));
+}
@@ -0,0 +1,117 @@
+#![allow(deprecated)]
+
+extern crate hamcrest;
+extern crate tempdir;
+extern crate npm_tools;
+
+mod utils;
+
+use npm_tools::{verify_repo, record_content_hashes, Finding, FsExecutor, Instruction, InstructionOwned, Visitor};
+use hamcrest::*;
+use tempdir::TempDir;
+use std::fs::{self, File, OpenOptions, create_dir_all};
+use std::io::Write;
+use std::os::unix::fs::symlink;
+
+#[test]
+fn a_consistent_repo_has_no_issues() {
+    let repo = utils::transient_repo_path();
+    utils::make_package(&repo.path().join("a").join("1.0.0"), "a", "1.0.0");
+    utils::make_package(&repo.path().join("@s").join("b").join("2.0.0"), "@s/b", "2.0.0");
+
+    assert_that(&verify_repo(repo.path()).unwrap(), of_len(0));
+}
+
+#[test]
+fn it_finds_packages_updated_in_place_without_proposing_a_repair() {
+    let repo = utils::transient_repo_path();
+    let dir = repo.path().join("a").join("1.0.0");
+    utils::make_package(&dir, "a", "1.2.0");
+
+    let issues = verify_repo(repo.path()).unwrap();
+    assert_that(&issues, of_len(1));
+    match issues[0].finding {
+        Finding::VersionMismatch { ref actual, .. } => assert_that(actual, equal_to(&String::from("1.2.0"))),
+        _ => unreachable!(),
+    }
+    assert_that(&issues[0].repair, equal_to(&None));
+}
+
+#[test]
+fn it_finds_dangling_symlinks_and_empty_version_directories() {
+    let repo = utils::transient_repo_path();
+    let dir = repo.path().join("a").join("1.0.0");
+    utils::make_package(&dir, "a", "1.0.0");
+    create_dir_all(dir.join("node_modules")).unwrap();
+    symlink(repo.path().join("b").join("1.0.0"), dir.join("node_modules").join("b")).unwrap();
+    create_dir_all(repo.path().join("c").join("1.0.0")).unwrap();
+
+    let issues = verify_repo(repo.path()).unwrap();
+    assert_that(&issues, of_len(2));
+    match (&issues[0].finding, &issues[0].repair) {
+        (&Finding::DanglingSymlink { .. }, &Some(InstructionOwned::RemoveSymlink { ref this_symlink })) => {
+            assert_that(this_symlink, equal_to(&dir.join("node_modules").join("b")))
+        }
+        _ => unreachable!(),
+    }
+    match (&issues[1].finding, &issues[1].repair) {
+        (&Finding::EmptyVersionDirectory { .. }, &Some(InstructionOwned::RemoveFromRepo { .. })) => {}
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn it_finds_packages_modified_since_their_hash_was_recorded() {
+    let repo = utils::transient_repo_path();
+    let dir = repo.path().join("a").join("1.0.0");
+    utils::make_package(&dir, "a", "1.0.0");
+
+    assert_that(record_content_hashes(repo.path()).unwrap(), equal_to(1));
+    assert_that(record_content_hashes(repo.path()).unwrap(), equal_to(0));
+    assert_that(&verify_repo(repo.path()).unwrap(), of_len(0));
+
+    OpenOptions::new().append(true).open(dir.join("package.json")).unwrap().write_all(b"\n").unwrap();
+    assert_that(&verify_repo(repo.path()).unwrap(), of_len(0));
+
+    File::create(dir.join("patched.js")).unwrap();
+    let issues = verify_repo(repo.path()).unwrap();
+    assert_that(&issues, of_len(1));
+    match issues[0].finding {
+        Finding::ModifiedSinceIngestion { .. } => {}
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn it_replaces_the_hashes_of_packages_removed_from_and_moved_into_the_repo() {
+    let repo = utils::transient_repo_path();
+    let project = TempDir::new("project").unwrap();
+    let dir = repo.path().join("a").join("1.0.0");
+    let stored_hash = repo.path().join(".npm-tools").join("hashes").join("a").join("1.0.0");
+    utils::make_package(&dir, "a", "1.0.0");
+    let ingest_modified_copy = |patch: &str| {
+        let copy = project.path().join("node_modules").join("a");
+        utils::make_package(&copy, "a", "1.0.0");
+        File::create(copy.join(patch)).unwrap();
+        FsExecutor::default()
+            .change(Instruction::MoveAndSymlink {
+                from_here: &copy,
+                to_here: &dir,
+                symlink_destination: &dir,
+            })
+            .unwrap();
+        fs::remove_file(&copy).unwrap();
+    };
+
+    assert_that(record_content_hashes(repo.path()).unwrap(), equal_to(1));
+    FsExecutor::default().change(Instruction::RemoveFromRepo { this_directory: &dir }).unwrap();
+    assert_that(stored_hash.exists(), equal_to(false));
+    ingest_modified_copy("patched.js");
+    assert_that(stored_hash.is_file(), equal_to(true));
+    assert_that(&verify_repo(repo.path()).unwrap(), of_len(0));
+
+    fs::remove_dir_all(&dir).unwrap();
+    ingest_modified_copy("patched-again.js");
+    assert_that(record_content_hashes(repo.path()).unwrap(), equal_to(0));
+    assert_that(&verify_repo(repo.path()).unwrap(), of_len(0));
+}
@@ -0,0 +1,89 @@
+#![allow(deprecated)]
+
+extern crate hamcrest;
+extern crate tempdir;
+extern crate npm_tools;
+
+mod utils;
+
+use npm_tools::{find_packages, find_packages_parallel, PackageInfo, Error};
+use hamcrest::*;
+use tempdir::TempDir;
+use std::fs::{File, create_dir_all};
+use std::os::unix::fs::symlink;
+
+fn collect(root: &std::path::Path) -> Vec<PackageInfo> {
+    find_packages(root).map(|r| r.unwrap()).collect()
+}
+
+#[test]
+fn it_finds_all_packages_of_a_flat_node_modules_directory() {
+    let root = utils::fixture_at("reveal.js-unnested");
+    let ps = collect(&root);
+
+    assert_that(&ps, of_len(514));
+    assert_that(ps.iter().all(|p| p.root_directory == root.join("node_modules")),
+                equal_to(true));
+}
+
+#[test]
+fn it_finds_the_same_packages_in_the_same_order_in_parallel() {
+    let root = utils::fixture_at("reveal.js-nested");
+    let ps: Vec<_> = find_packages_parallel(&root, 4).into_iter().map(|r| r.unwrap()).collect();
+    assert_that(&ps, equal_to(&collect(&root)));
+
+    let missing = find_packages_parallel(root.join("missing"), 4);
+    assert_that(missing.len(), equal_to(1));
+    assert_that(missing[0].is_err(), equal_to(true));
+}
+
+#[test]
+fn it_descends_into_nested_node_modules_depth_first() {
+    let root = utils::fixture_at("reveal.js-nested");
+    let ps = collect(&root);
+    assert_that(&ps, of_len(365));
+
+    let cssmin = root.join("node_modules").join("grunt-contrib-cssmin");
+    let pos = ps.iter().position(|p| p.directory == cssmin).unwrap();
+    assert_that(&ps[pos + 1].directory,
+                equal_to(&cssmin.join("node_modules").join("chalk")));
+}
+
+#[test]
+fn it_skips_symlinks_and_directories_without_package_json() {
+    let project = TempDir::new("project").unwrap();
+    let node_modules = project.path().join("node_modules");
+    create_dir_all(node_modules.join("a")).unwrap();
+    create_dir_all(node_modules.join(".bin")).unwrap();
+    File::create(node_modules.join("a").join("package.json")).unwrap();
+    symlink(node_modules.join("a"), node_modules.join("b")).unwrap();
+
+    let ps = collect(project.path());
+    assert_that(&ps, of_len(1));
+    assert_that(&ps[0].directory, equal_to(&node_modules.join("a")));
+}
+
+#[test]
+fn it_reports_a_missing_node_modules_directory_as_error() {
+    let project = TempDir::new("project").unwrap();
+
+    let rs: Vec<_> = find_packages(project.path()).collect();
+    assert_that(&rs, of_len(1));
+    match rs[0] {
+        Err(Error::ReadDirectory(ref p, _)) => assert_that(p, equal_to(&project.path().join("node_modules"))),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn it_finds_scoped_packages_within_their_scope_directory() {
+    let project = TempDir::new("project").unwrap();
+    let scoped = project.path().join("node_modules").join("@babel").join("core");
+    create_dir_all(&scoped).unwrap();
+    File::create(scoped.join("package.json")).unwrap();
+
+    let ps = collect(project.path());
+    assert_that(&ps, of_len(1));
+    assert_that(&ps[0].directory, equal_to(&scoped));
+    assert_that(ps[0].name(), equal_to("@babel/core".as_ref()));
+}
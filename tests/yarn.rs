@@ -0,0 +1,244 @@
+#![allow(deprecated)]
+
+extern crate hamcrest;
+extern crate tempdir;
+extern crate npm_tools;
+
+mod utils;
+
+use std::fs::File;
+use std::io::Write;
+use npm_tools::{DependencySpec, Error, YarnDescriptor, YarnLock, find_yarn_locked_packages, read_yarn_lock};
+use hamcrest::*;
+use tempdir::TempDir;
+
+fn read(contents: &str) -> Result<YarnLock, Error> {
+    let project = TempDir::new("project").unwrap();
+    File::create(project.path().join("yarn.lock")).unwrap().write_all(contents.as_bytes()).unwrap();
+    read_yarn_lock(project.path())
+}
+
+fn descriptor(name: &str, range: &str) -> YarnDescriptor {
+    YarnDescriptor {
+        name: name.to_owned(),
+        range: range.to_owned(),
+    }
+}
+
+const V1: &str = r#"# THIS IS AN AUTOGENERATED FILE. DO NOT EDIT THIS FILE DIRECTLY.
+# yarn lockfile v1
+
+
+"@babel/code-frame@^7.0.0", "@babel/code-frame@^7.10.4":
+  version "7.12.13"
+  resolved "https://registry.yarnpkg.com/@babel/code-frame/-/code-frame-7.12.13.tgz#dcfc826b"
+  integrity sha512-HV1Cm0Q3ZrpCR93tkWOYiuYIgLxZXZFVG2VgK+MBWjUqZTundupbfx2aXarXuw5Ko5aMcjtJgbSs4vUGBS5v6g==
+  dependencies:
+    "@babel/highlight" "^7.12.13"
+
+"@babel/highlight@^7.12.13":
+  version "7.13.10"
+  dependencies:
+    chalk "^2.0.0"
+  optionalDependencies:
+    fsevents "~2.3.1"
+
+chalk@^2.0.0:
+  version "2.4.2"
+
+fsevents@~2.3.1:
+  version "2.3.2"
+
+"lo@npm:lodash@^4.17.0":
+  version "4.17.21"
+"#;
+
+const BERRY: &str = r#"# This file is generated by running "yarn install" inside your project.
+# Manual changes might be lost - proceed with caution!
+
+__metadata:
+  version: 6
+  cacheKey: 8
+
+"@babel/code-frame@npm:^7.0.0, @babel/code-frame@npm:^7.10.4":
+  version: 7.12.13
+  resolution: "@babel/code-frame@npm:7.12.13"
+  dependencies:
+    "@babel/highlight": ^7.12.13
+  checksum: 471532bb7cb4a300bd1a3201e75e7c0c83ebfb4e0e6610fdb53270521505d7efe0961258de61e7b1970ef3092a97ed67
+  languageName: node
+  linkType: hard
+
+"@babel/highlight@npm:^7.12.13":
+  version: 7.13.10
+  resolution: "@babel/highlight@npm:7.13.10"
+  dependencies:
+    chalk: ^2.0.0
+  dependenciesMeta:
+    fsevents:
+      optional: true
+  languageName: node
+  linkType: hard
+
+"lo@npm:lodash@^4.17.0":
+  version: 4.17.21
+  resolution: "lodash@npm:4.17.21"
+  languageName: node
+  linkType: hard
+
+"project@workspace:.":
+  version: 0.0.0-use.local
+  resolution: "project@workspace:."
+  dependencies:
+    "@babel/code-frame": ^7.10.4
+    lo: "npm:lodash@^4.17.0"
+  languageName: unknown
+  linkType: soft
+"#;
+
+#[test]
+fn it_reads_yarn_v1_lockfiles() {
+    let lock = read(V1).unwrap();
+    assert_that(lock.berry, equal_to(false));
+    assert_that(&lock.entries, of_len(5));
+
+    let frame = &lock.entries[0];
+    assert_that(&frame.descriptors,
+                equal_to(&vec![descriptor("@babel/code-frame", "^7.0.0"), descriptor("@babel/code-frame", "^7.10.4")]));
+    assert_that(&*frame.name, equal_to("@babel/code-frame"));
+    assert_that(&*frame.version, equal_to("7.12.13"));
+    assert_that(frame.resolved.as_deref().unwrap().starts_with("https://registry.yarnpkg.com/"), equal_to(true));
+    assert_that(frame.integrity.as_deref().unwrap().starts_with("sha512-HV1Cm0Q3"), equal_to(true));
+    assert_that(&frame.dependencies, equal_to(&vec![descriptor("@babel/highlight", "^7.12.13")]));
+
+    let highlight = &lock.entries[1];
+    assert_that(&highlight.dependencies, equal_to(&vec![descriptor("chalk", "^2.0.0")]));
+    assert_that(&highlight.optional_dependencies, equal_to(&vec![descriptor("fsevents", "~2.3.1")]));
+    assert_that(&*lock.entries[4].name, equal_to("lodash"));
+}
+
+#[test]
+fn it_reads_yarn_berry_lockfiles() {
+    let lock = read(BERRY).unwrap();
+    assert_that(lock.berry, equal_to(true));
+    assert_that(&lock.entries, of_len(4));
+
+    let frame = &lock.entries[0];
+    assert_that(&frame.descriptors,
+                equal_to(&vec![descriptor("@babel/code-frame", "^7.0.0"), descriptor("@babel/code-frame", "^7.10.4")]));
+    assert_that(&*frame.name, equal_to("@babel/code-frame"));
+    assert_that(frame.resolved.as_deref(), equal_to(Some("@babel/code-frame@npm:7.12.13")));
+    assert_that(frame.integrity.as_deref().unwrap().starts_with("471532bb"), equal_to(true));
+
+    let highlight = &lock.entries[1];
+    assert_that(&highlight.dependencies, equal_to(&vec![descriptor("chalk", "^2.0.0")]));
+    assert_that(&highlight.optional_dependencies, of_len(0));
+
+    let alias = &lock.entries[2];
+    assert_that(&*alias.name, equal_to("lodash"));
+    assert_that(&alias.descriptors, equal_to(&vec![descriptor("lo", "npm:lodash@^4.17.0")]));
+    assert_that(&*lock.entries[3].name, equal_to("project"));
+}
+
+#[test]
+fn it_resolves_the_dependencies_of_entries_to_other_entries() {
+    for lock in &[read(V1).unwrap(), read(BERRY).unwrap()] {
+        let resolved = lock.resolve("@babel/code-frame", "^7.10.4").unwrap();
+        assert_that(&*resolved.version, equal_to("7.12.13"));
+        for dependency in &resolved.dependencies {
+            let dependency = lock.resolve(&dependency.name, &dependency.range).unwrap();
+            assert_that(&*dependency.name, equal_to("@babel/highlight"));
+            assert_that(&*dependency.version, equal_to("7.13.10"));
+        }
+
+        let alias = lock.resolve("lo", "npm:lodash@^4.17.0").unwrap();
+        assert_that(&*alias.version, equal_to("4.17.21"));
+        assert_that(matches!(alias.descriptors[0].spec(), Ok(DependencySpec::Alias { .. })), equal_to(true));
+        assert_that(lock.resolve("@babel/code-frame", "^7.11.0").is_none(), equal_to(true));
+    }
+}
+
+#[test]
+fn it_rejects_unexpected_structures() {
+    assert_that(matches!(read("lodash@^1.0.0:\n  resolved \"https://example.com\"\n"), Err(Error::Lockfile(..))),
+                equal_to(true));
+    assert_that(matches!(read("lodash\n  version \"1.0.0\"\n"), Err(Error::Lockfile(..))),
+                equal_to(true));
+
+    let project = TempDir::new("project").unwrap();
+    match read_yarn_lock(project.path()) {
+        Err(Error::Lockfile(ref p, _)) => assert_that(p, equal_to(&project.path().join("yarn.lock"))),
+        _ => unreachable!(),
+    }
+}
+
+const INSTALLED: &str = r#"# yarn lockfile v1
+
+a@^1.0.0:
+  version "1.0.0"
+  dependencies:
+    b "^1.0.0"
+    c "^1.0.0"
+  optionalDependencies:
+    fsevents "~2.3.1"
+
+b@^1.0.0:
+  version "1.5.0"
+
+b@^2.0.0:
+  version "2.0.0"
+
+c@^1.0.0:
+  version "1.0.0"
+  dependencies:
+    a "^1.0.0"
+
+fsevents@~2.3.1:
+  version "2.3.2"
+
+"lo@npm:lodash@^4.17.0":
+  version "4.17.21"
+"#;
+
+#[test]
+fn it_locates_the_packages_yarn_resolved_dependencies_to_like_node() {
+    let project = TempDir::new("project").unwrap();
+    let root = project.path();
+    utils::write_file(&root.join("yarn.lock"), INSTALLED);
+    utils::write_file(&root.join("package.json"),
+                      r#"{"dependencies": {"a": "^1.0.0", "lo": "npm:lodash@^4.17.0", "lib": "file:../lib"},
+                          "devDependencies": {"b": "^2.0.0"}}"#);
+    for dir in &["a", "a/node_modules/b", "b", "c", "lo", "stray"] {
+        utils::write_file(&root.join("node_modules").join(dir).join("package.json"), "{}");
+    }
+
+    let locked = find_yarn_locked_packages(root).unwrap();
+    let summary: Vec<_> = locked.iter()
+        .map(|p| {
+            (p.info.directory.strip_prefix(root).unwrap().to_string_lossy().into_owned(),
+             format!("{}@{}", p.name, p.version))
+        })
+        .collect();
+    let expected: Vec<_> = [("node_modules/a", "a@1.0.0"),
+                            ("node_modules/a/node_modules/b", "b@1.5.0"),
+                            ("node_modules/b", "b@2.0.0"),
+                            ("node_modules/c", "c@1.0.0"),
+                            ("node_modules/lo", "lodash@4.17.21")]
+        .iter()
+        .map(|&(d, p)| (d.to_owned(), p.to_owned()))
+        .collect();
+    assert_that(summary, equal_to(expected));
+    assert_that(locked.iter().all(|p| p.info.root_directory == root.join("node_modules")),
+                equal_to(true));
+}
+
+#[test]
+fn it_rejects_dependencies_missing_from_yarn_lock_or_with_invalid_names() {
+    let project = TempDir::new("project").unwrap();
+    let root = project.path();
+    utils::write_file(&root.join("yarn.lock"), INSTALLED);
+    for dependencies in &[r#"{"a": "^3.0.0"}"#, r#"{"../evil": "^1.0.0"}"#] {
+        utils::write_file(&root.join("package.json"), &format!(r#"{{"dependencies": {}}}"#, dependencies));
+        assert_that(matches!(find_yarn_locked_packages(root), Err(Error::Lockfile(..))), equal_to(true));
+    }
+}